@@ -1,34 +1,54 @@
-use core::fmt;
+use core::{cmp::Ordering, fmt};
 use std::{
-	collections::HashMap,
+	borrow::Cow,
+	collections::{BTreeMap, HashMap, HashSet},
 	error::Error,
 	io,
 	iter::Peekable,
 	ops::{Deref, DerefMut},
 	path::{Path, PathBuf},
 	str::{Chars, FromStr},
+	sync::{Arc, Mutex, MutexGuard},
 };
 
-use crate::{options::IncludeMethod, Options};
+use crate::{
+	escape::Escaper,
+	expression::{parse_named_argument, parse_string_literal, Expression},
+	filter::{parse_variable, split_unquoted, Filter, BUILTIN_FILTERS},
+	format::FormatSpec,
+	options::{Escape, IncludeMethod},
+	scope::Scope,
+	value::VariablePath,
+	Options, Value,
+};
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct Document {
 	options: Options,
 	template_path: Option<PathBuf>,
 	pub(crate) tokens: Vec<Token>,
-	variables: HashMap<String, String>,
-	patterns: HashMap<String, Vec<String>>,
+	pub(crate) variables: HashMap<String, Value>,
+	pub(crate) patterns: HashMap<String, Vec<Pattern>>,
+	/// The macros defined in, or imported into, the template
+	pub(crate) macros: HashMap<String, Macro>,
+	/// Templates imported with a name, like `{%import components.bpl as ui}`
+	imports: HashMap<String, Document>,
+	/// Includes read while compiling. Clones of a document share these.
+	pub(crate) includes: IncludeCache,
 }
 
 impl Document {
 	/// Attempt to read an entire file and parse it as a Document
 	pub fn from_file<P: AsRef<Path>>(path: P, options: Options) -> Result<Self, ParseError> {
 		let doc = Self {
+			variables: options.variables.clone(),
 			options,
 			template_path: Some(path.as_ref().to_owned()),
 			tokens: vec![],
-			variables: HashMap::new(),
 			patterns: HashMap::new(),
+			macros: HashMap::new(),
+			imports: HashMap::new(),
+			includes: IncludeCache::default(),
 		};
 
 		doc.parse_string(Self::read_to_string(path)?)
@@ -43,11 +63,14 @@ impl Document {
 
 	pub fn from_str<S: AsRef<str>>(s: S, options: Options) -> Result<Self, ParseError> {
 		Document {
+			variables: options.variables.clone(),
 			options,
 			template_path: None,
 			tokens: vec![],
-			variables: HashMap::new(),
 			patterns: HashMap::new(),
+			macros: HashMap::new(),
+			imports: HashMap::new(),
+			includes: IncludeCache::default(),
 		}
 		.parse_string(s)
 	}
@@ -60,92 +83,401 @@ impl Document {
 
 	/// Set a variable with the given key to the given value
 	pub fn set<K: Into<String>, V: fmt::Display>(&mut self, key: K, value: V) {
-		self.variables.insert(key.into(), format!("{}", value));
+		self.variables
+			.insert(key.into(), Value::String(format!("{}", value)));
+	}
+
+	/// Set a variable that is never escaped, even when the document escapes
+	/// every other variable. Only use this for values you trust.
+	pub fn set_raw<K: Into<String>, V: fmt::Display>(&mut self, key: K, value: V) {
+		self.variables
+			.insert(key.into(), Value::Safe(format!("{}", value)));
+	}
+
+	/// Set a variable with the given key to a structured [Value]. Unlike
+	/// [Document::set] the value is not turned into a string until the
+	/// document is compiled.
+	pub fn set_value<K: Into<String>, V: Into<Value>>(&mut self, key: K, value: V) {
+		self.variables.insert(key.into(), value.into());
 	}
 
-	/// Get pattern
+	/// Get a blank instance of the pattern with the given name. Any variables
+	/// not set on the instance are taken from this document when it is
+	/// compiled.
+	///
+	/// Patterns are found no matter how deep they're nested in other
+	/// commands. If there are patterns with the same name inside different
+	/// patterns, give the path to the one you want, like `table/row` for the
+	/// pattern `row` inside the pattern `table`.
+	///
+	/// The returned pattern keeps the name given here. When it's set with
+	/// [Document::set_pattern] it's used wherever the pattern appears in the
+	/// document, including inside every instance of an enclosing pattern
+	/// that doesn't have its own instances set.
+	///
+	/// Patterns from a template imported with `{%import file as name}` are
+	/// found with the name in front, like `ui.card`.
 	pub fn get_pattern<K: Into<String>>(&self, key: K) -> Option<Pattern> {
 		let key = key.into();
 
-		self.tokens.iter().find_map(|tok| {
-			if let Token::Pattern {
-				pattern_name,
-				tokens,
-			} = tok
-			{
-				if *pattern_name == key {
-					Some(Pattern {
-						name: key.clone(),
-						document: Document {
-							options: self.options.clone(),
-							template_path: self.template_path.clone(),
-							tokens: tokens.clone(),
-							variables: self.variables.clone(),
-							patterns: HashMap::new(),
-						},
-					})
-				} else {
-					None
-				}
-			} else {
-				None
+		if let Some((namespace, rest)) = key.split_once('.') {
+			if let Some(imported) = self.imports.get(namespace) {
+				let mut pattern = imported.get_pattern(rest)?;
+				pattern.set_name(key);
+				return Some(pattern);
 			}
+		}
+
+		let path: Vec<&str> = key.split('/').collect();
+
+		find_pattern(&self.tokens, &path).map(|tokens| Pattern {
+			name: key.clone(),
+			key: None,
+			document: Document {
+				options: self.options.clone(),
+				template_path: self.template_path.clone(),
+				tokens: tokens.to_vec(),
+				variables: HashMap::new(),
+				patterns: HashMap::new(),
+				macros: HashMap::new(),
+				imports: HashMap::new(),
+				includes: self.includes.clone(),
+			},
 		})
 	}
 
-	pub fn set_pattern(&mut self, Pattern { name, document }: Pattern) {
-		match self.patterns.get_mut(&name) {
-			Some(pats) => pats.push(document.compile()),
+	/// Add an instance of a pattern to the document. Instances are compiled
+	/// along with the document, in the order they were set, and have the
+	/// same `loop` variables available to them as the body of a `{%for}`.
+	pub fn set_pattern(&mut self, pattern: Pattern) {
+		match self.patterns.get_mut(&pattern.name) {
+			Some(pats) => pats.push(pattern),
 			None => {
-				self.patterns.insert(name, vec![document.compile()]);
+				self.patterns.insert(pattern.name.clone(), vec![pattern]);
 			}
 		}
 	}
 
+	/// Get the instances set for the pattern `name`, in the order they'll be
+	/// compiled.
+	pub fn pattern_instances<K: AsRef<str>>(&self, name: K) -> &[Pattern] {
+		self.patterns
+			.get(name.as_ref())
+			.map(|pats| &pats[..])
+			.unwrap_or_default()
+	}
+
+	/// Add an instance of a pattern at `index`, moving every instance after
+	/// it along by one.
+	pub fn insert_pattern(&mut self, index: usize, pattern: Pattern) -> Result<(), PatternError> {
+		let len = self.pattern_instances(&pattern.name).len();
+
+		if index > len {
+			return Err(PatternError::IndexOutOfBounds {
+				name: pattern.name,
+				index,
+				len,
+			});
+		}
+
+		self.patterns
+			.entry(pattern.name.clone())
+			.or_default()
+			.insert(index, pattern);
+		Ok(())
+	}
+
+	/// Replace the instance at `index` with another instance of the same
+	/// pattern, returning the one that was there.
+	pub fn replace_pattern(
+		&mut self,
+		index: usize,
+		pattern: Pattern,
+	) -> Result<Pattern, PatternError> {
+		let pats = self.patterns.get_mut(&pattern.name);
+		let len = pats.as_ref().map(|pats| pats.len()).unwrap_or(0);
+
+		match pats.and_then(|pats| pats.get_mut(index)) {
+			Some(old) => Ok(std::mem::replace(old, pattern)),
+			None => Err(PatternError::IndexOutOfBounds {
+				name: pattern.name,
+				index,
+				len,
+			}),
+		}
+	}
+
+	/// Replace the instance that has the same key as `pattern`, returning the
+	/// one that was there. See [Pattern::set_key].
+	pub fn replace_pattern_keyed(&mut self, pattern: Pattern) -> Result<Pattern, PatternError> {
+		let found = pattern.key.as_ref().and_then(|key| {
+			self.patterns
+				.get_mut(&pattern.name)?
+				.iter_mut()
+				.find(|pat| pat.key.as_ref() == Some(key))
+		});
+
+		match found {
+			Some(old) => Ok(std::mem::replace(old, pattern)),
+			None => Err(PatternError::UnknownKey {
+				name: pattern.name,
+				key: pattern.key.unwrap_or_default(),
+			}),
+		}
+	}
+
+	/// Remove the instance at `index` of the pattern `name`.
+	pub fn remove_pattern<K: AsRef<str>>(&mut self, name: K, index: usize) -> Option<Pattern> {
+		let pats = self.patterns.get_mut(name.as_ref())?;
+
+		if index < pats.len() {
+			Some(pats.remove(index))
+		} else {
+			None
+		}
+	}
+
+	/// Remove the instance of the pattern `name` that has the given key.
+	/// See [Pattern::set_key].
+	pub fn remove_pattern_keyed<K: AsRef<str>, Q: AsRef<str>>(
+		&mut self,
+		name: K,
+		key: Q,
+	) -> Option<Pattern> {
+		let pats = self.patterns.get_mut(name.as_ref())?;
+		let index = pats
+			.iter()
+			.position(|pat| pat.key.as_deref() == Some(key.as_ref()))?;
+
+		Some(pats.remove(index))
+	}
+
+	/// Remove every instance of the pattern `name`.
+	pub fn clear_pattern<K: AsRef<str>>(&mut self, name: K) {
+		self.patterns.remove(name.as_ref());
+	}
+
+	/// Sort the instances of the pattern `name`. The sort is stable.
+	pub fn sort_pattern_by<K, F>(&mut self, name: K, compare: F)
+	where
+		K: AsRef<str>,
+		F: FnMut(&Pattern, &Pattern) -> Ordering,
+	{
+		if let Some(pats) = self.patterns.get_mut(name.as_ref()) {
+			pats.sort_by(compare);
+		}
+	}
+
+	/// Get the value of a variable that was set on this document.
+	pub fn get<K: AsRef<str>>(&self, key: K) -> Option<&Value> {
+		self.variables.get(key.as_ref())
+	}
+
+	/// Fill the pattern `name` with one instance for every item. The closure
+	/// is given a blank instance and the item and should set whatever
+	/// variables it needs.
+	///
+	/// Returns an error, and sets nothing, if the pattern can't be found.
+	pub fn fill_pattern<K, I, F>(
+		&mut self,
+		name: K,
+		items: I,
+		mut fill: F,
+	) -> Result<(), PatternError>
+	where
+		K: Into<String>,
+		I: IntoIterator,
+		F: FnMut(&mut Pattern, I::Item),
+	{
+		let name = name.into();
+		let pattern = self
+			.get_pattern(name.clone())
+			.ok_or(PatternError::UnknownPattern { name })?;
+
+		for item in items {
+			let mut instance = pattern.clone();
+			fill(&mut instance, item);
+			self.set_pattern(instance);
+		}
+
+		Ok(())
+	}
+
+	/// Fill the pattern `name` with one instance for every map, like a
+	/// `HashMap<String, String>`, setting every key in the map as a variable.
+	pub fn fill_pattern_maps<K, I, M, MK, MV>(
+		&mut self,
+		name: K,
+		items: I,
+	) -> Result<(), PatternError>
+	where
+		K: Into<String>,
+		I: IntoIterator<Item = M>,
+		M: IntoIterator<Item = (MK, MV)>,
+		MK: Into<String>,
+		MV: Into<Value>,
+	{
+		self.fill_pattern(name, items, |pattern, map| {
+			for (key, value) in map {
+				pattern.set_value(key, value);
+			}
+		})
+	}
+
+	/// Fill the pattern `name` with one instance for every item, setting each
+	/// field of the item as a variable. Every item must serialize to a map,
+	/// like a struct does.
+	///
+	/// If any item fails, none are set.
+	#[cfg(feature = "serde")]
+	pub fn fill_pattern_serialize<K, I, T>(&mut self, name: K, items: I) -> Result<(), PatternError>
+	where
+		K: Into<String>,
+		I: IntoIterator<Item = T>,
+		T: serde::Serialize,
+	{
+		let maps = items
+			.into_iter()
+			.map(|item| match Value::from_serialize(&item) {
+				Ok(Value::Map(map)) => Ok(map),
+				Ok(_) => Err(PatternError::InvalidItem {
+					reason: String::from("the item is not a map"),
+				}),
+				Err(e) => Err(PatternError::InvalidItem {
+					reason: e.to_string(),
+				}),
+			})
+			.collect::<Result<Vec<_>, _>>()?;
+
+		self.fill_pattern_maps(name, maps)
+	}
+
 	/// Compile the document into a string. If you set a value for a variable,
 	/// it will be replaced. If you have not, the declaration is passed through.
 	/// IE: If you have {variable} and do not set a value, it'll come through
 	/// with the braces and all.
-	pub fn compile(mut self) -> String {
-		let tokens = self.tokens.drain(..).collect();
-		self.tokens_to_string(tokens)
+	pub fn compile(self) -> String {
+		self.tokens_to_string(&self.tokens, &Scope::new(&self))
 	}
 
-	fn tokens_to_string(&self, tokens: Vec<Token>) -> String {
+	fn tokens_to_string(&self, tokens: &[Token], scope: &Scope) -> String {
 		let mut ret = String::new();
 
 		for token in tokens {
 			match token {
-				Token::Text(str) => ret.push_str(&str),
-				Token::Variable { name } => match self.variables.get(&name) {
-					Some(value) => ret.push_str(value),
-					None => {
-						ret.push('{');
-						ret.push_str(&name);
-						ret.push('}');
-					}
-				},
+				Token::Text(str) => ret.push_str(str),
+				Token::Variable {
+					path,
+					format,
+					filters,
+				} => ret.push_str(&self.variable_to_string(path, format.as_ref(), filters, scope)),
 				Token::IfSet {
-					variable_name,
+					variable,
 					tokens,
 					else_tokens,
-				} => match (self.variables.get(&variable_name), else_tokens) {
-					(Some(val), _) if !val.is_empty() => {
-						ret.push_str(&self.tokens_to_string(tokens))
+				} => match (scope.lookup(variable), else_tokens) {
+					(Some(val), _) if val.is_truthy() => {
+						ret.push_str(&self.tokens_to_string(tokens, scope))
+					}
+					(_, Some(else_tokens)) => {
+						ret.push_str(&self.tokens_to_string(else_tokens, scope))
 					}
-					(_, Some(else_tokens)) => ret.push_str(&self.tokens_to_string(else_tokens)),
 					_ => (),
 				},
-				Token::Pattern { pattern_name, .. } => {
-					if let Some(pat) = self.patterns.get(&pattern_name) {
-						for compiled_pattern in pat {
-							ret.push_str(compiled_pattern);
+				Token::If {
+					condition,
+					tokens,
+					else_tokens,
+				} => {
+					if condition.is_true(scope) {
+						ret.push_str(&self.tokens_to_string(tokens, scope))
+					} else if let Some(else_tokens) = else_tokens {
+						ret.push_str(&self.tokens_to_string(else_tokens, scope))
+					}
+				}
+				Token::IfUnset {
+					variable,
+					tokens,
+					else_tokens,
+				} => match (scope.lookup(variable), else_tokens) {
+					(Some(val), Some(else_tokens)) if val.is_truthy() => {
+						ret.push_str(&self.tokens_to_string(else_tokens, scope))
+					}
+					(Some(val), None) if val.is_truthy() => (),
+					_ => ret.push_str(&self.tokens_to_string(tokens, scope)),
+				},
+				Token::IfPattern {
+					pattern_name,
+					tokens,
+					else_tokens,
+				} => match (scope.instances(pattern_name), else_tokens) {
+					(Some(_), _) => ret.push_str(&self.tokens_to_string(tokens, scope)),
+					(None, Some(else_tokens)) => {
+						ret.push_str(&self.tokens_to_string(else_tokens, scope))
+					}
+					(None, None) => (),
+				},
+				Token::For {
+					variable,
+					iterable,
+					tokens,
+				} => {
+					if let Some(Value::List(items)) = scope.lookup(iterable).as_deref() {
+						for (index, item) in items.iter().enumerate() {
+							let mut variables = HashMap::new();
+							variables.insert(variable.clone(), item.clone());
+							variables.insert("loop".into(), loop_variables(index, items.len()));
+
+							ret.push_str(
+								&self.tokens_to_string(tokens, &scope.child(Cow::Owned(variables))),
+							);
 						}
 					}
 				}
+				Token::Pattern {
+					pattern_name,
+					separator,
+					empty_tokens,
+					..
+				} => match scope.instances(pattern_name) {
+					Some(pat) => ret.push_str(&Self::instances_to_string(
+						pattern_name,
+						pat,
+						separator.as_deref(),
+						scope,
+					)),
+					None => {
+						if let Some(empty_tokens) = empty_tokens {
+							ret.push_str(&self.tokens_to_string(empty_tokens, scope))
+						}
+					}
+				},
+				Token::Children { pattern_name } => {
+					if let Some(children) = scope.children(pattern_name) {
+						if scope.depth() < self.options.recursion_limit {
+							ret.push_str(&Self::instances_to_string(
+								pattern_name,
+								children,
+								None,
+								scope,
+							))
+						}
+					}
+				}
+				Token::Include {
+					path,
+					arguments,
+					only,
+				} => ret.push_str(&self.include_to_string(path, arguments, *only, scope)),
+				Token::Call { name, arguments } => {
+					ret.push_str(&self.call_to_string(name, arguments, scope))
+				}
+				Token::Macro { .. } => (),
 				Token::WrapInclude { .. } => (),
 				Token::WrappedContent => (),
 				Token::Else => (),
+				Token::ElseIf(_) => (),
+				Token::Empty => (),
 				Token::End => (),
 			}
 		}
@@ -153,6 +485,203 @@ impl Document {
 		ret
 	}
 
+	/// Read, parse and compile an include. Each file is only parsed the first
+	/// time it's included. If the path can't be resolved, or the file can't
+	/// be read or parsed, the include is passed through as it was written.
+	///
+	/// The arguments take precedence over any `{%set}` in the included file.
+	/// Includes deeper than the recursion limit are left out.
+	fn include_to_string(
+		&self,
+		path: &str,
+		arguments: &[(String, Expression)],
+		only: bool,
+		scope: &Scope,
+	) -> String {
+		if scope.depth() >= self.options.recursion_limit {
+			return String::new();
+		}
+
+		let included = interpolate(path, |var| scope.lookup(var).map(|val| val.to_string()))
+			.and_then(|path| self.resolve_include_path(path).ok())
+			.and_then(|resolved| {
+				self.includes.get_or_parse(resolved, |resolved| {
+					self.parse_included(Self::read_to_string(resolved).ok()?)
+						.ok()
+				})
+			});
+
+		match included {
+			Some(doc) => {
+				let arguments = arguments
+					.iter()
+					.map(|(name, value)| (name.clone(), value.evaluate(scope).into_owned()))
+					.collect();
+
+				let include_scope = scope.include(&doc, only);
+				doc.tokens_to_string(&doc.tokens, &include_scope.child(Cow::Owned(arguments)))
+			}
+			None => {
+				let mut ret = format!("{{%include {}", path);
+				for (name, value) in arguments {
+					ret.push_str(&format!(" {}={}", name, value));
+				}
+				if only {
+					ret.push_str(" only");
+				}
+				ret.push('}');
+				ret
+			}
+		}
+	}
+
+	/// Compile a call to a macro. Parameters without an argument take their
+	/// default, if they have one, and are left unset if they don't. If the
+	/// macro can't be found, or the calls go deeper than the recursion limit,
+	/// the call is passed through as it was written.
+	fn call_to_string(
+		&self,
+		name: &str,
+		arguments: &[(String, Expression)],
+		scope: &Scope,
+	) -> String {
+		let found = scope
+			.find_macro(name)
+			.filter(|_| scope.depth() < self.options.recursion_limit);
+
+		let Some(found) = found else {
+			let mut ret = format!("{{%call {}", name);
+			for (name, value) in arguments {
+				ret.push_str(&format!(" {}={}", name, value));
+			}
+			ret.push('}');
+			return ret;
+		};
+
+		let mut variables: HashMap<String, Value> = found
+			.parameters
+			.iter()
+			.filter_map(|(name, default)| {
+				let value = default.as_ref()?.evaluate(scope).into_owned();
+				Some((name.clone(), value))
+			})
+			.collect();
+
+		for (name, value) in arguments {
+			variables.insert(name.clone(), value.evaluate(scope).into_owned());
+		}
+
+		self.tokens_to_string(&found.tokens, &scope.call(found, variables))
+	}
+
+	/// How variables are escaped in this document. [Escape::Auto] is resolved
+	/// from the extension of the template.
+	fn escaping(&self) -> Cow<'_, Escape> {
+		match self.options.escape {
+			Escape::Auto => {
+				let extension = self
+					.template_path
+					.as_ref()
+					.and_then(|path| path.extension())
+					.and_then(|ext| ext.to_str());
+
+				Cow::Owned(
+					extension
+						.map(Escape::from_extension)
+						.unwrap_or(Escape::Never),
+				)
+			}
+			ref escape => Cow::Borrowed(escape),
+		}
+	}
+
+	/// Get the value of a variable, run it through its filters and format it.
+	/// The value is escaped unless it's [Value::Safe]. If the variable isn't
+	/// set, or a filter fails, it's passed through as it was written.
+	fn variable_to_string(
+		&self,
+		path: &VariablePath,
+		format: Option<&FormatSpec>,
+		filters: &[Filter],
+		scope: &Scope,
+	) -> String {
+		let value = match scope.lookup(path) {
+			Some(value) => Some(value.into_owned()),
+			None if filters.iter().any(Filter::applies_to_unset) => Some(Value::Null),
+			None => None,
+		};
+
+		let filtered = value.and_then(|value| {
+			filters
+				.iter()
+				.try_fold(value, |value, filter| {
+					filter.apply(value, &self.options.filters, scope)
+				})
+				.ok()
+		});
+
+		match filtered {
+			Some(value) => {
+				let string = match format {
+					Some(format) => format.format(&value),
+					None => value.to_string(),
+				};
+
+				let escaping = self.escaping();
+				// The json filter already wrote JSON, so escaping it as the inside
+				// of a JSON string would break it
+				let is_json = filters.last().is_some_and(|filter| filter.name == "json");
+
+				match value {
+					Value::Safe(_) => string,
+					_ if is_json && matches!(*escaping, Escape::Json) => string,
+					_ => escaping.escape(&string),
+				}
+			}
+			None => {
+				let mut ret = format!("{{{}", path);
+				if let Some(format) = format {
+					ret.push(':');
+					ret.push_str(&format.to_string());
+				}
+				for filter in filters {
+					ret.push('|');
+					ret.push_str(&filter.to_string());
+				}
+				ret.push('}');
+				ret
+			}
+		}
+	}
+
+	fn instances_to_string(
+		pattern_name: &str,
+		instances: &[Pattern],
+		separator: Option<&str>,
+		scope: &Scope,
+	) -> String {
+		let mut ret = String::new();
+
+		for (index, instance) in instances.iter().enumerate() {
+			if let (Some(sep), true) = (separator, index > 0) {
+				ret.push_str(sep);
+			}
+
+			let mut variables = HashMap::new();
+			variables.insert("loop".into(), loop_variables(index, instances.len()));
+
+			// Instances see the variables of the document they're in, as
+			// they are now, but their own take precedence
+			let instance_scope = scope.instance(pattern_name, &instance.document);
+			ret.push_str(&instance.tokens_to_string(
+				&instance.tokens,
+				&instance_scope.child(Cow::Owned(variables)),
+			));
+		}
+
+		ret
+	}
+
 	fn do_command_structuring(
 		mut command: Token,
 		iter: &mut impl Iterator<Item = Token>,
@@ -160,31 +689,96 @@ impl Document {
 		loop {
 			let token = match iter.next() {
 				Some(Token::End) => return Ok(command),
+				// Macros can only be defined at the top of a template
+				Some(Token::Macro { .. }) => {
+					return Err(ParseError::MisplacedCommand {
+						command: String::from("macro"),
+					})
+				}
 				Some(tok) if tok.is_command() => Self::do_command_structuring(tok, iter)?,
 				Some(tok) => tok,
 				None => return Err(ParseError::UnclosedCommand),
 			};
 
+			// Only conditionals can be chained
+			if matches!(token, Token::ElseIf(_)) && !command.is_conditional() {
+				return Err(ParseError::MisplacedCommand {
+					command: String::from("else-if"),
+				});
+			}
+
+			if matches!(token, Token::Empty) && !matches!(command, Token::Pattern { .. }) {
+				return Err(ParseError::MisplacedCommand {
+					command: String::from("empty"),
+				});
+			}
+
 			match command {
 				Token::IfSet {
 					ref mut tokens,
 					ref mut else_tokens,
 					..
+				}
+				| Token::If {
+					ref mut tokens,
+					ref mut else_tokens,
+					..
+				}
+				| Token::IfUnset {
+					ref mut tokens,
+					ref mut else_tokens,
+					..
+				}
+				| Token::IfPattern {
+					ref mut tokens,
+					ref mut else_tokens,
+					..
 				} => match token {
 					Token::Else => {
 						*else_tokens = Some(vec![]);
 					}
+					Token::ElseIf(_) if else_tokens.is_some() => {
+						return Err(ParseError::MisplacedCommand {
+							command: String::from("else-if"),
+						})
+					}
+					Token::ElseIf(conditional) => {
+						// The rest of the chain is a conditional in our else branch
+						// and shares our end, so it takes the end when it's done
+						let chained = Self::do_command_structuring(*conditional, iter)?;
+						*else_tokens = Some(vec![chained]);
+						return Ok(command);
+					}
 					_ => match else_tokens {
 						None => tokens.push(token),
 						Some(tok) => tok.push(token),
 					},
 				},
-				Token::Pattern { ref mut tokens, .. } => tokens.push(token),
+				Token::For { ref mut tokens, .. } => tokens.push(token),
+				Token::Pattern {
+					ref mut tokens,
+					ref mut empty_tokens,
+					..
+				} => match token {
+					Token::Empty => {
+						*empty_tokens = Some(vec![]);
+					}
+					_ => match empty_tokens {
+						None => tokens.push(token),
+						Some(tok) => tok.push(token),
+					},
+				},
 				Token::WrapInclude { ref mut tokens, .. } => tokens.push(token),
+				Token::Macro { ref mut tokens, .. } => tokens.push(token),
 				Token::Text(_)
 				| Token::Variable { .. }
+				| Token::Children { .. }
+				| Token::Include { .. }
+				| Token::Call { .. }
 				| Token::WrappedContent
 				| Token::Else
+				| Token::ElseIf(_)
+				| Token::Empty
 				| Token::End => {
 					panic!("Should not be able to get here!")
 				}
@@ -201,6 +795,9 @@ impl Document {
 			tokens,
 			variables,
 			patterns,
+			mut macros,
+			imports,
+			includes,
 		} = self;
 
 		let mut iter = tokens.into_iter();
@@ -213,6 +810,7 @@ impl Document {
 					let wrap = Self::do_command_structuring(wrap, &mut iter)?;
 
 					let (doc, mut toks) = if let Token::WrapInclude { document, tokens } = wrap {
+						macros.extend(document.macros);
 						(document.tokens.into_iter(), tokens)
 					} else {
 						unreachable!()
@@ -220,14 +818,33 @@ impl Document {
 
 					for tok in doc {
 						if let Token::WrappedContent = tok {
-							doc_tokens.extend(toks.drain(..));
+							doc_tokens.append(&mut toks);
 						} else {
 							doc_tokens.push(tok);
 						}
 					}
 				}
 				Some(tok) if tok.is_command() => {
-					doc_tokens.push(Self::do_command_structuring(tok, &mut iter)?)
+					match Self::do_command_structuring(tok, &mut iter)? {
+						Token::Macro {
+							name,
+							parameters,
+							tokens,
+						} => {
+							macros.insert(name, Macro { parameters, tokens });
+						}
+						tok => doc_tokens.push(tok),
+					}
+				}
+				Some(Token::ElseIf(_)) => {
+					return Err(ParseError::MisplacedCommand {
+						command: String::from("else-if"),
+					})
+				}
+				Some(Token::Empty) => {
+					return Err(ParseError::MisplacedCommand {
+						command: String::from("empty"),
+					})
 				}
 				Some(tok) => doc_tokens.push(tok),
 				None => break,
@@ -240,6 +857,9 @@ impl Document {
 			tokens: doc_tokens,
 			variables,
 			patterns,
+			macros,
+			imports,
+			includes,
 		})
 	}
 
@@ -266,11 +886,11 @@ impl Document {
 					let inside = match chars.peek() {
 						Some('%') => {
 							// We're a command, take everything until the next '}'
-							take_while_chars(&mut chars, |ch| *ch != '}')
+							take_inside_braces(&mut chars, false)
 						}
 						Some(_ch) => {
 							// We're a variable, no whitespace!
-							take_while_chars(&mut chars, |ch| *ch != '}' && !ch.is_whitespace())
+							take_inside_braces(&mut chars, true)
 						}
 						None => {
 							current.push('{');
@@ -323,7 +943,25 @@ impl Document {
 					None => self.parse_command(stripped_and_trimmed, None)?,
 				}
 			}
-			Some(_) => self.tokens.push(Token::Variable { name: s.into() }),
+			Some(_) => match parse_variable(s) {
+				// A filter we don't know means this probably isn't a variable,
+				// like the `{y|=1}` in some inline javascript, so pass it through
+				Some((_, _, filters))
+					if filters.iter().any(|filter| {
+						!BUILTIN_FILTERS.contains(&filter.name.as_str())
+							&& !self.options.filters.contains(&filter.name)
+					}) =>
+				{
+					self.tokens.push(Token::Text(format!("{{{}}}", s)))
+				}
+				Some((path, format, filters)) => self.tokens.push(Token::Variable {
+					path,
+					format,
+					filters,
+				}),
+				// Not a valid variable, so we're not a variable. Pass it through
+				None => self.tokens.push(Token::Text(format!("{{{}}}", s))),
+			},
 		}
 
 		Ok(())
@@ -337,11 +975,25 @@ impl Document {
 			})
 		};
 
+		if let Some(conditional @ ("if" | "if-set" | "if-unset" | "if-pattern")) =
+			command.strip_prefix("else-")
+		{
+			self.parse_command(conditional, arguments)?;
+
+			let conditional = self.tokens.pop().unwrap();
+			self.tokens.push(Token::ElseIf(Box::new(conditional)));
+			return Ok(());
+		}
+
 		match command {
 			"else" => {
 				self.tokens.push(Token::Else);
 				return Ok(());
 			}
+			"empty" => {
+				self.tokens.push(Token::Empty);
+				return Ok(());
+			}
 			"end" => {
 				self.tokens.push(Token::End);
 				return Ok(());
@@ -355,38 +1007,250 @@ impl Document {
 
 		let arguments = match arguments {
 			None => return invalid_arguments(),
-			Some(args) if args.is_empty() => return invalid_arguments(),
+			Some("") => return invalid_arguments(),
 			Some(args) => args,
 		};
 
 		// Reaching here means we have arguments and they are not an empty string
 		match command {
-			"set" => match arguments.split_once(" ") {
-				None => return invalid_arguments(),
+			"set" => match arguments.split_once(' ') {
+				None => invalid_arguments(),
 				Some((name, value)) => {
-					self.variables.insert(name.to_owned(), value.to_owned());
+					self.variables
+						.insert(name.to_owned(), Value::String(value.to_owned()));
 					Ok(())
 				}
 			},
+			"macro" => {
+				let mut words = split_unquoted(arguments, " ")
+					.into_iter()
+					.filter(|word| !word.is_empty());
+
+				let name = match words.next() {
+					Some(name) if !name.contains(['.', '=']) => name.to_owned(),
+					_ => return invalid_arguments(),
+				};
+
+				let parameters = words
+					.map(|word| match parse_named_argument(word) {
+						Some((param, default)) => Some((param, Some(default))),
+						None if !word.contains(['.', '=', '"']) => Some((word.to_owned(), None)),
+						None => None,
+					})
+					.collect::<Option<Vec<_>>>();
+
+				match parameters {
+					Some(parameters) => {
+						self.tokens.push(Token::Macro {
+							name,
+							parameters,
+							tokens: vec![],
+						});
+						Ok(())
+					}
+					None => invalid_arguments(),
+				}
+			}
+			"call" => {
+				let mut words = split_unquoted(arguments, " ")
+					.into_iter()
+					.filter(|word| !word.is_empty());
+
+				let name = match words.next() {
+					Some(name) => name.to_owned(),
+					None => return invalid_arguments(),
+				};
+
+				match words.map(parse_named_argument).collect() {
+					Some(arguments) => {
+						self.tokens.push(Token::Call { name, arguments });
+						Ok(())
+					}
+					None => invalid_arguments(),
+				}
+			}
+			"import" => {
+				let (path, namespace) = match arguments.split_once(" as ") {
+					None => (arguments, None),
+					Some((path, namespace)) => {
+						let namespace = namespace.trim();
+						if namespace.is_empty() || namespace.contains(['.', '/', ' ']) {
+							return invalid_arguments();
+						}
+
+						(path.trim(), Some(namespace))
+					}
+				};
+
+				let resolved = self.resolve_include_path(path)?;
+				let mut imported = self.parse_included(Self::read_to_string(resolved)?)?;
+
+				match namespace {
+					None => self.macros.extend(imported.macros),
+					Some(namespace) => {
+						// Calls between the imported macros have to use the
+						// namespace now, too
+						let names: Vec<String> = imported.macros.keys().cloned().collect();
+						let qualify =
+							|tokens: &mut Vec<Token>| qualify_calls(tokens, namespace, &names);
+
+						qualify(&mut imported.tokens);
+						for found in imported.macros.values_mut() {
+							qualify(&mut found.tokens);
+						}
+
+						for (name, found) in imported.macros.drain() {
+							self.macros.insert(format!("{}.{}", namespace, name), found);
+						}
+						self.imports.insert(namespace.to_owned(), imported);
+					}
+				}
+
+				Ok(())
+			}
+			"include" if self.options.lazy_includes || include_arguments(arguments).is_some() => {
+				let (path, arguments, only) =
+					include_arguments(arguments).unwrap_or((arguments, vec![], false));
+
+				// Includes with arguments are still read now, unless they're
+				// lazy, so a missing file is an error. Only the arguments wait
+				// until the document is compiled.
+				let path = match self.interpolate_set(path) {
+					Some(path) if !self.options.lazy_includes => {
+						let resolved = self.resolve_include_path(&path)?;
+						self.includes.parse(resolved, |resolved| {
+							self.parse_included(Self::read_to_string(resolved)?)
+						})?;
+						path
+					}
+					_ => path.to_owned(),
+				};
+
+				self.tokens.push(Token::Include {
+					path,
+					arguments,
+					only,
+				});
+				Ok(())
+			}
 			"include" => {
-				let resolved = self.resolve_include_path(arguments)?;
-				let string = Self::read_to_string(resolved)?;
-				self.first_pass(string)?;
+				match self.interpolate_set(arguments) {
+					Some(path) => {
+						let resolved = self.resolve_include_path(path)?;
+						let string = Self::read_to_string(resolved)?;
+						self.first_pass(string)?;
+					}
+					// Some variable in the path isn't set yet, so wait until
+					// the document is compiled
+					None => self.tokens.push(Token::Include {
+						path: arguments.to_owned(),
+						arguments: vec![],
+						only: false,
+					}),
+				}
+
 				Ok(())
 			}
 			"if-set" => {
+				let variable = match VariablePath::parse(arguments) {
+					Some(path) => path,
+					None => return invalid_arguments(),
+				};
+
 				self.tokens.push(Token::IfSet {
-					variable_name: arguments.into(),
+					variable,
+					tokens: vec![],
+					else_tokens: None,
+				});
+
+				Ok(())
+			}
+			"if" => {
+				let condition = match Expression::parse(arguments) {
+					Some(expr) => expr,
+					None => return invalid_arguments(),
+				};
+
+				self.tokens.push(Token::If {
+					condition,
+					tokens: vec![],
+					else_tokens: None,
+				});
+
+				Ok(())
+			}
+			"if-unset" | "unless" => {
+				let variable = match VariablePath::parse(arguments) {
+					Some(path) => path,
+					None => return invalid_arguments(),
+				};
+
+				self.tokens.push(Token::IfUnset {
+					variable,
+					tokens: vec![],
+					else_tokens: None,
+				});
+
+				Ok(())
+			}
+			"if-pattern" => {
+				self.tokens.push(Token::IfPattern {
+					pattern_name: arguments.into(),
 					tokens: vec![],
 					else_tokens: None,
 				});
 
 				Ok(())
 			}
+			"for" => {
+				let (variable, iterable) = match arguments.split_whitespace().collect::<Vec<_>>()[..]
+				{
+					[variable, "in", iterable] => (variable, iterable),
+					_ => return invalid_arguments(),
+				};
+
+				let iterable = match VariablePath::parse(iterable) {
+					Some(path) => path,
+					None => return invalid_arguments(),
+				};
+
+				// The loop variable is a single name, it can't be a path itself
+				if variable.contains('.') {
+					return invalid_arguments();
+				}
+
+				self.tokens.push(Token::For {
+					variable: variable.to_owned(),
+					iterable,
+					tokens: vec![],
+				});
+
+				Ok(())
+			}
 			"pattern" => {
+				let (pattern_name, separator) = match arguments.split_once(' ') {
+					None => (arguments, None),
+					Some((name, options)) => match options.trim().strip_prefix("sep=") {
+						Some(sep) => match parse_string_literal(sep) {
+							Some(sep) => (name, Some(sep)),
+							None => return invalid_arguments(),
+						},
+						None => return invalid_arguments(),
+					},
+				};
+
 				self.tokens.push(Token::Pattern {
-					pattern_name: arguments.into(),
+					pattern_name: pattern_name.into(),
+					separator,
 					tokens: vec![],
+					empty_tokens: None,
+				});
+
+				Ok(())
+			}
+			"children" => {
+				self.tokens.push(Token::Children {
+					pattern_name: arguments.into(),
 				});
 
 				Ok(())
@@ -397,7 +1261,7 @@ impl Document {
 				let doc = Document::from_str(&string, self.options.clone())?;
 
 				self.tokens.push(Token::WrapInclude {
-					document: doc,
+					document: Box::new(doc),
 					tokens: vec![],
 				});
 
@@ -409,6 +1273,34 @@ impl Document {
 		}
 	}
 
+	/// Parse a template included from this one. Its includes are resolved
+	/// the same way as this document's.
+	/// Fill in the variables in an include path with those set so far, by
+	/// `{%set}` or [Options::variable]. Returns `None` if any aren't set.
+	fn interpolate_set(&self, path: &str) -> Option<String> {
+		interpolate(path, |var| {
+			self.variables
+				.get(var.root())
+				.and_then(|val| val.get_path(var.rest()))
+				.filter(|val| !val.is_null())
+				.map(|val| val.to_string())
+		})
+	}
+
+	fn parse_included(&self, string: String) -> Result<Document, ParseError> {
+		Document {
+			options: self.options.clone(),
+			template_path: self.template_path.clone(),
+			tokens: vec![],
+			variables: HashMap::new(),
+			patterns: HashMap::new(),
+			macros: HashMap::new(),
+			imports: HashMap::new(),
+			includes: self.includes.clone(),
+		}
+		.parse_string(string)
+	}
+
 	fn resolve_include_path<P: AsRef<Path>>(&self, path: P) -> Result<PathBuf, ParseError> {
 		match self.options.include_method {
 			IncludeMethod::Path(ref buf) => {
@@ -467,80 +1359,579 @@ impl FromStr for Document {
 	}
 }
 
-fn take_while_chars(iter: &mut Peekable<Chars>, func: impl Fn(&char) -> bool) -> String {
+/// Find the body of the pattern at `path`, searching through every command.
+/// Each segment of the path after the first must be inside the pattern
+/// named by the segment before it.
+fn find_pattern<'t>(tokens: &'t [Token], path: &[&str]) -> Option<&'t [Token]> {
+	tokens.iter().find_map(|tok| {
+		if let Token::Pattern {
+			pattern_name,
+			tokens,
+			..
+		} = tok
+		{
+			if pattern_name == path[0] {
+				let found = match path {
+					[_] => Some(&tokens[..]),
+					[_, rest @ ..] => find_pattern(tokens, rest),
+					[] => unreachable!(),
+				};
+
+				if found.is_some() {
+					return found;
+				}
+			}
+		}
+
+		tok.bodies()
+			.into_iter()
+			.find_map(|body| find_pattern(body, path))
+	})
+}
+
+/// The `loop` variable available inside `{%for}` and pattern instances. It's
+/// a map of
+/// - `index`: the iteration, counting from 1
+/// - `index0`: the iteration, counting from 0
+/// - `first`: true on the first iteration
+/// - `last`: true on the last iteration
+/// - `length`: the total number of iterations
+fn loop_variables(index: usize, length: usize) -> Value {
+	let mut map = BTreeMap::new();
+	map.insert("index".into(), Value::from(index + 1));
+	map.insert("index0".into(), Value::from(index));
+	map.insert("first".into(), Value::Bool(index == 0));
+	map.insert("last".into(), Value::Bool(index + 1 == length));
+	map.insert("length".into(), Value::from(length));
+
+	Value::Map(map)
+}
+
+/// Take everything up until the closing brace, leaving it in the iterator.
+/// Double quoted strings are taken whole, so they may contain braces and
+/// whitespace, unless a quote is never closed. Then quotes are ignored.
+fn take_inside_braces(iter: &mut Peekable<Chars>, stop_at_whitespace: bool) -> String {
+	let start = iter.clone();
 	let mut ret = String::new();
+	let mut quoted = false;
+	// Commands can have variables in them, like an include path
+	let mut depth = 0;
+
+	while let Some(&ch) = iter.peek() {
+		match ch {
+			'{' if !quoted && !stop_at_whitespace => depth += 1,
+			'}' if !quoted && depth > 0 => depth -= 1,
+			'}' if !quoted => break,
+			ch if ch.is_whitespace() && stop_at_whitespace && !quoted => {
+				// Whitespace is allowed around the ?? of an inline default
+				let mut lookahead = iter.clone();
+				let mut whitespace = String::new();
+				while let Some(ch) = lookahead.next_if(|ch| ch.is_whitespace()) {
+					whitespace.push(ch);
+				}
+
+				let around_default = ret.ends_with("??")
+					|| (lookahead.next() == Some('?') && lookahead.next() == Some('?'));
+
+				if !around_default {
+					break;
+				}
+
+				ret.push_str(&whitespace);
+				for _ in 0..whitespace.chars().count() {
+					iter.next();
+				}
+				continue;
+			}
+			'"' => quoted = !quoted,
+			'\\' if quoted => {
+				// Keep the escape and whatever it escapes
+				ret.push(ch);
+				iter.next();
+
+				if let Some(escaped) = iter.next() {
+					ret.push(escaped);
+				}
+				continue;
+			}
+			_ => (),
+		}
 
-	loop {
-		match iter.peek() {
-			Some(ch) if func(ch) => ret.push(iter.next().unwrap()),
-			_ => break,
+		ret.push(ch);
+		iter.next();
+	}
+
+	if quoted || depth > 0 {
+		*iter = start;
+		ret.clear();
+
+		while let Some(ch) =
+			iter.next_if(|ch| *ch != '}' && !(stop_at_whitespace && ch.is_whitespace()))
+		{
+			ret.push(ch);
 		}
 	}
 
 	ret
 }
 
+/// The documents that are compiled as includes, by their resolved path.
+/// Includes are parsed the first time they're needed and shared from then
+/// on, even between clones of the document.
+#[derive(Clone, Default)]
+pub(crate) struct IncludeCache {
+	documents: Arc<Mutex<HashMap<PathBuf, Arc<Document>>>>,
+	/// The includes being parsed along with the document
+	parsing: Arc<Mutex<HashSet<PathBuf>>>,
+}
+
+impl IncludeCache {
+	/// Get the document at `path`, parsing it with `parse` if it hasn't been
+	/// yet. Failures aren't remembered.
+	fn get_or_parse<F>(&self, path: PathBuf, parse: F) -> Option<Arc<Document>>
+	where
+		F: FnOnce(&Path) -> Option<Document>,
+	{
+		if let Some(doc) = self.lock().get(&path) {
+			return Some(Arc::clone(doc));
+		}
+
+		// Not holding the lock while parsing keeps it free for any includes
+		// being compiled on other threads
+		let doc = Arc::new(parse(&path)?);
+		self.lock().insert(path, Arc::clone(&doc));
+		Some(doc)
+	}
+
+	/// Parse the document at `path` while the document including it is
+	/// parsed, so it fails if the include does. An include of a document that
+	/// is already being parsed is skipped, as it'll be here by the time the
+	/// include is compiled.
+	fn parse<F>(&self, path: PathBuf, parse: F) -> Result<(), ParseError>
+	where
+		F: FnOnce(&Path) -> Result<Document, ParseError>,
+	{
+		if self.lock().contains_key(&path) || !self.lock_parsing().insert(path.clone()) {
+			return Ok(());
+		}
+
+		let parsed = parse(&path);
+		self.lock_parsing().remove(&path);
+		self.lock().insert(path, Arc::new(parsed?));
+		Ok(())
+	}
+
+	fn lock_parsing(&self) -> MutexGuard<'_, HashSet<PathBuf>> {
+		self.parsing
+			.lock()
+			.unwrap_or_else(|poisoned| poisoned.into_inner())
+	}
+
+	fn lock(&self) -> MutexGuard<'_, HashMap<PathBuf, Arc<Document>>> {
+		// The map is never left half-changed, so a poisoned lock is still fine
+		self.documents
+			.lock()
+			.unwrap_or_else(|poisoned| poisoned.into_inner())
+	}
+
+	#[cfg(test)]
+	pub(crate) fn len(&self) -> usize {
+		self.lock().len()
+	}
+}
+
+impl fmt::Debug for IncludeCache {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_set().entries(self.lock().keys()).finish()
+	}
+}
+
+impl PartialEq for IncludeCache {
+	/// What's been included so far doesn't change what a document is
+	fn eq(&self, _: &Self) -> bool {
+		true
+	}
+}
+
+/// The path of an include, its named arguments and whether it's `only`
+type IncludeArguments<'s> = (&'s str, Vec<(String, Expression)>, bool);
+
+/// Split the arguments of an include like `card.bpl title="Hi" only` into
+/// the path, the named arguments and whether it's `only`. Returns `None` if
+/// there are none of either, or if anything after the path isn't one, in
+/// which case it's all the path.
+fn include_arguments(s: &str) -> Option<IncludeArguments<'_>> {
+	let mut words = split_unquoted(s, " ")
+		.into_iter()
+		.filter(|word| !word.is_empty());
+	let path = words.next()?;
+
+	let mut arguments = vec![];
+	let mut only = false;
+	for word in words {
+		match word {
+			"only" => only = true,
+			word => arguments.push(parse_named_argument(word)?),
+		}
+	}
+
+	if arguments.is_empty() && !only {
+		None
+	} else {
+		Some((path, arguments, only))
+	}
+}
+
+/// Put `namespace` in front of every call to one of the macros in `names`
+fn qualify_calls(tokens: &mut [Token], namespace: &str, names: &[String]) {
+	for token in tokens {
+		if let Token::Call { name, .. } = token {
+			if names.contains(name) {
+				*name = format!("{}.{}", namespace, name);
+			}
+		}
+
+		for body in token.bodies_mut() {
+			qualify_calls(body, namespace, names);
+		}
+	}
+}
+
+/// Replace every `{variable}` in `s` with its value. Returns `None` if any
+/// of them aren't set or aren't valid variables.
+fn interpolate<F>(s: &str, lookup: F) -> Option<String>
+where
+	F: Fn(&VariablePath) -> Option<String>,
+{
+	let mut ret = String::new();
+	let mut rest = s;
+
+	while let Some(start) = rest.find('{') {
+		let end = rest[start..].find('}')? + start;
+		let path = VariablePath::parse(&rest[start + 1..end])?;
+
+		ret.push_str(&rest[..start]);
+		ret.push_str(&lookup(&path)?);
+		rest = &rest[end + 1..];
+	}
+
+	ret.push_str(rest);
+	Some(ret)
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub enum Token {
 	Text(String),
 	Variable {
-		name: String,
+		path: VariablePath,
+		/// How to pad, align and round the value, like the `>10.2` in
+		/// `{amount:>10.2}`
+		format: Option<FormatSpec>,
+		filters: Vec<Filter>,
 	},
 	IfSet {
-		variable_name: String,
+		variable: VariablePath,
+		tokens: Vec<Token>,
+		else_tokens: Option<Vec<Token>>,
+	},
+	/// Uses the tokens if the condition is true, and the else tokens if not.
+	If {
+		condition: Expression,
+		tokens: Vec<Token>,
+		else_tokens: Option<Vec<Token>>,
+	},
+	/// The opposite of `IfSet`. The tokens are used when the variable is not
+	/// set or is empty.
+	IfUnset {
+		variable: VariablePath,
 		tokens: Vec<Token>,
 		else_tokens: Option<Vec<Token>>,
 	},
+	/// Like `IfSet`, but tests whether or not any instances of the pattern
+	/// were set.
+	IfPattern {
+		pattern_name: String,
+		tokens: Vec<Token>,
+		else_tokens: Option<Vec<Token>>,
+	},
+	/// Repeats its tokens once for every item in the list at `iterable`,
+	/// with the item available as `variable`.
+	For {
+		variable: String,
+		iterable: VariablePath,
+		tokens: Vec<Token>,
+	},
+	/// Where the instances of a pattern are placed. The `separator` goes
+	/// between every instance and the `empty_tokens`, everything after an
+	/// `{%empty}`, are used when there are no instances at all.
 	Pattern {
 		pattern_name: String,
+		separator: Option<String>,
 		tokens: Vec<Token>,
+		empty_tokens: Option<Vec<Token>>,
+	},
+	/// The children of the current instance of a recursive pattern. Only
+	/// valid inside the pattern it names.
+	Children {
+		pattern_name: String,
 	},
 	WrapInclude {
-		document: Document,
+		document: Box<Document>,
+		tokens: Vec<Token>,
+	},
+	/// An include that is compiled on its own instead of being put into the
+	/// document. Either [Options::lazy_includes] is set, it has arguments, or
+	/// its path has variables, like `themes/{theme}/header.bpl`, that weren't
+	/// set when it was parsed. Unless it's lazy, an include with arguments
+	/// and a path that's known is still read when the document is parsed.
+	Include {
+		path: String,
+		/// Variables set for the include, like the `title="Hello"` in
+		/// `{%include card.bpl title="Hello"}`
+		arguments: Vec<(String, Expression)>,
+		/// Whether the include can only see its arguments
+		only: bool,
+	},
+	/// The definition of a macro. These are moved out of the tokens and into
+	/// the document once it's parsed.
+	Macro {
+		name: String,
+		/// The names of the parameters and their defaults
+		parameters: Vec<(String, Option<Expression>)>,
 		tokens: Vec<Token>,
 	},
+	/// A call to a macro, like `{%call button label="Buy"}`
+	Call {
+		name: String,
+		arguments: Vec<(String, Expression)>,
+	},
 	WrappedContent,
 	Else,
+	/// An else branch that is itself a conditional, like `{%else-if-set}`.
+	/// These only exist until the document is structured, at which point the
+	/// conditional is moved into the else tokens of the one before it.
+	ElseIf(Box<Token>),
+	Empty,
 	End,
 }
 
 impl Token {
+	/// Every list of tokens inside this one. An `IfSet`, for example, has
+	/// its tokens and the else tokens, if there are any.
+	pub(crate) fn bodies(&self) -> Vec<&[Token]> {
+		match self {
+			Token::IfSet {
+				tokens,
+				else_tokens,
+				..
+			}
+			| Token::If {
+				tokens,
+				else_tokens,
+				..
+			}
+			| Token::IfUnset {
+				tokens,
+				else_tokens,
+				..
+			}
+			| Token::IfPattern {
+				tokens,
+				else_tokens,
+				..
+			} => std::iter::once(tokens)
+				.chain(else_tokens.as_ref())
+				.map(|toks| &toks[..])
+				.collect(),
+			Token::For { tokens, .. } => vec![tokens],
+			Token::Pattern {
+				tokens,
+				empty_tokens,
+				..
+			} => std::iter::once(tokens)
+				.chain(empty_tokens.as_ref())
+				.map(|toks| &toks[..])
+				.collect(),
+			Token::WrapInclude { tokens, .. } | Token::Macro { tokens, .. } => vec![tokens],
+			Token::Text(_)
+			| Token::Variable { .. }
+			| Token::Children { .. }
+			| Token::Include { .. }
+			| Token::Call { .. }
+			| Token::WrappedContent
+			| Token::Else
+			| Token::ElseIf(_)
+			| Token::Empty
+			| Token::End => vec![],
+		}
+	}
+
+	/// Every list of tokens inside this one, like [Token::bodies]
+	pub(crate) fn bodies_mut(&mut self) -> Vec<&mut Vec<Token>> {
+		match self {
+			Token::IfSet {
+				tokens,
+				else_tokens,
+				..
+			}
+			| Token::If {
+				tokens,
+				else_tokens,
+				..
+			}
+			| Token::IfUnset {
+				tokens,
+				else_tokens,
+				..
+			}
+			| Token::IfPattern {
+				tokens,
+				else_tokens,
+				..
+			} => std::iter::once(tokens)
+				.chain(else_tokens.as_mut())
+				.collect(),
+			Token::For { tokens, .. } => vec![tokens],
+			Token::Pattern {
+				tokens,
+				empty_tokens,
+				..
+			} => std::iter::once(tokens)
+				.chain(empty_tokens.as_mut())
+				.collect(),
+			Token::WrapInclude { tokens, .. } | Token::Macro { tokens, .. } => vec![tokens],
+			Token::Text(_)
+			| Token::Variable { .. }
+			| Token::Children { .. }
+			| Token::Include { .. }
+			| Token::Call { .. }
+			| Token::WrappedContent
+			| Token::Else
+			| Token::ElseIf(_)
+			| Token::Empty
+			| Token::End => vec![],
+		}
+	}
+
+	/// Whether this is one of the if commands, which can have an else
+	/// branch and be chained with else-if
+	pub fn is_conditional(&self) -> bool {
+		matches!(
+			self,
+			Token::IfSet { .. }
+				| Token::If { .. }
+				| Token::IfUnset { .. }
+				| Token::IfPattern { .. }
+		)
+	}
+
 	pub fn is_command(&self) -> bool {
 		match self {
 			Token::Text(_) => false,
 			Token::Variable { .. } => false,
 			Token::IfSet { .. } => true,
+			Token::If { .. } => true,
+			Token::IfUnset { .. } => true,
+			Token::IfPattern { .. } => true,
+			Token::For { .. } => true,
 			Token::Pattern { .. } => true,
+			Token::Children { .. } => false,
 			Token::WrapInclude { .. } => true,
+			Token::Include { .. } => false,
+			Token::Macro { .. } => true,
+			Token::Call { .. } => false,
 			Token::WrappedContent => false,
 			Token::Else => false,
+			Token::ElseIf(_) => false,
+			Token::Empty => false,
 			Token::End => false,
 		}
 	}
 }
 
+/// A macro defined with `{%macro name parameters...}`
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Macro {
+	/// The names of the parameters and their defaults
+	pub parameters: Vec<(String, Option<Expression>)>,
+	pub tokens: Vec<Token>,
+}
+
 /// A loose container over a [Document]. This struct is just the [Document]
-/// inside the pattern and the name itself.
-#[derive(Clone, Debug)]
+/// inside the pattern, the name itself, and an optional key that identifies
+/// the instance once it's been set.
+#[derive(Clone, Debug, PartialEq)]
 pub struct Pattern {
 	name: String,
+	key: Option<String>,
 	pub document: Document,
 }
 
 impl Pattern {
 	pub fn new(name: String, document: Document) -> Self {
-		Self { name, document }
+		Self {
+			name,
+			key: None,
+			document,
+		}
 	}
 
 	pub fn name(&self) -> &str {
 		&self.name
 	}
 
+	pub fn key(&self) -> Option<&str> {
+		self.key.as_deref()
+	}
+
+	/// Give this instance a key so it can be found again after it's been set
+	/// with [Document::replace_pattern_keyed] and
+	/// [Document::remove_pattern_keyed].
+	pub fn set_key<K: Into<String>>(&mut self, key: K) {
+		self.key = Some(key.into());
+	}
+
 	/// Changes the name of this pattern which determines where it is placed in
 	/// the [Document] when [Document::set_pattern] is called.
 	pub fn set_name(&mut self, name: String) {
 		self.name = name;
 	}
+
+	/// Get a blank instance of this same pattern, to be added to this one
+	/// with [Pattern::add_child].
+	pub fn child(&self) -> Pattern {
+		Pattern {
+			name: self.name.clone(),
+			key: None,
+			document: Document {
+				options: self.document.options.clone(),
+				template_path: self.document.template_path.clone(),
+				tokens: self.document.tokens.clone(),
+				variables: HashMap::new(),
+				patterns: HashMap::new(),
+				macros: HashMap::new(),
+				imports: HashMap::new(),
+				includes: self.document.includes.clone(),
+			},
+		}
+	}
+
+	/// Add a child instance that is placed where this pattern has a
+	/// `{%children}` command naming itself. Children can have children of
+	/// their own, up to the [recursion limit](crate::Options::recursion_limit).
+	pub fn add_child(&mut self, child: Pattern) {
+		let name = pattern_base_name(&self.name).to_owned();
+
+		self.document.set_pattern(Pattern { name, ..child });
+	}
+}
+
+/// The name of a pattern in the template it's written in, without the path
+/// it was found at or the name it was imported under. `{%children}` names
+/// the pattern it's in like this, so `table/row` and `ui.row` are both `row`.
+pub(crate) fn pattern_base_name(name: &str) -> &str {
+	let name = name.rsplit('/').next().unwrap_or_default();
+	name.rsplit('.').next().unwrap_or_default()
 }
 
 impl Deref for Pattern {
@@ -579,9 +1970,59 @@ pub enum ParseError {
 		include_path: PathBuf,
 		from_buffer_template: bool,
 	},
+	MisplacedCommand {
+		command: String,
+	},
 	UnclosedCommand,
 }
 
+#[derive(Debug)]
+pub enum PatternError {
+	UnknownPattern {
+		name: String,
+	},
+	InvalidItem {
+		reason: String,
+	},
+	IndexOutOfBounds {
+		name: String,
+		index: usize,
+		len: usize,
+	},
+	UnknownKey {
+		name: String,
+		key: String,
+	},
+}
+
+impl Error for PatternError {}
+impl fmt::Display for PatternError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			PatternError::UnknownPattern { name } => {
+				write!(f, "There is no pattern named '{}' in the document", name)
+			}
+			PatternError::InvalidItem { reason } => {
+				write!(f, "Could not fill a pattern from an item: {}", reason)
+			}
+			PatternError::IndexOutOfBounds { name, index, len } => {
+				write!(
+					f,
+					"The index {} is out of bounds for the pattern '{}' which has {} instances",
+					index, name, len
+				)
+			}
+			PatternError::UnknownKey { name, key } => {
+				write!(
+					f,
+					"The pattern '{}' has no instance with the key '{}'",
+					name, key
+				)
+			}
+		}
+	}
+}
+
 impl Error for ParseError {}
 impl fmt::Display for ParseError {
 	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -628,6 +2069,9 @@ impl fmt::Display for ParseError {
 					)
 				}
 			}
+			ParseError::MisplacedCommand { command } => {
+				write!(f, "The command {} is not allowed here", command)
+			}
 			//FIXME: gen- this isn't cute, write a real error
 			Self::UnclosedCommand => write!(f, "No end in sight.."),
 		}
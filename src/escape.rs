@@ -0,0 +1,116 @@
+/// Makes a string safe to put somewhere in the output, like HTML text or a
+/// URL. It's given the value of a variable after its filters and format.
+///
+/// Any `Fn(&str) -> String` is an escaper. Use one with
+/// [Escape::custom](crate::Escape::custom).
+pub trait Escaper: Send + Sync {
+	fn escape(&self, s: &str) -> String;
+}
+
+impl<F> Escaper for F
+where
+	F: Fn(&str) -> String + Send + Sync,
+{
+	fn escape(&self, s: &str) -> String {
+		self(s)
+	}
+}
+
+/// Escape the characters that are special in HTML text and quoted attribute
+/// values
+pub(crate) fn html(s: &str) -> String {
+	let mut ret = String::with_capacity(s.len());
+
+	for ch in s.chars() {
+		match ch {
+			'&' => ret.push_str("&amp;"),
+			'<' => ret.push_str("&lt;"),
+			'>' => ret.push_str("&gt;"),
+			'"' => ret.push_str("&quot;"),
+			'\'' => ret.push_str("&#39;"),
+			ch => ret.push(ch),
+		}
+	}
+
+	ret
+}
+
+/// Escape every ASCII character that isn't a letter or number as a
+/// character reference, so the value is safe even in an unquoted attribute
+pub(crate) fn html_attribute(s: &str) -> String {
+	let mut ret = String::with_capacity(s.len());
+
+	for ch in s.chars() {
+		if ch.is_ascii_alphanumeric() || !ch.is_ascii() {
+			ret.push(ch);
+		} else {
+			ret.push_str(&format!("&#x{:02X};", ch as u32));
+		}
+	}
+
+	ret
+}
+
+/// Escape the five characters XML predefines entities for
+pub(crate) fn xml(s: &str) -> String {
+	let mut ret = String::with_capacity(s.len());
+
+	for ch in s.chars() {
+		match ch {
+			'&' => ret.push_str("&amp;"),
+			'<' => ret.push_str("&lt;"),
+			'>' => ret.push_str("&gt;"),
+			'"' => ret.push_str("&quot;"),
+			'\'' => ret.push_str("&apos;"),
+			ch => ret.push(ch),
+		}
+	}
+
+	ret
+}
+
+/// Escape the inside of a JSON string, without the quotes. `<`, `>` and `&`
+/// are escaped too so the string can't close a `<script>` element.
+pub(crate) fn json_string(s: &str) -> String {
+	let mut ret = String::with_capacity(s.len());
+
+	for ch in s.chars() {
+		match ch {
+			'"' => ret.push_str("\\\""),
+			'\\' => ret.push_str("\\\\"),
+			'\n' => ret.push_str("\\n"),
+			'\r' => ret.push_str("\\r"),
+			'\t' => ret.push_str("\\t"),
+			'<' | '>' | '&' | '\u{2028}' | '\u{2029}' => {
+				ret.push_str(&format!("\\u{:04x}", ch as u32))
+			}
+			ch if (ch as u32) < 0x20 => ret.push_str(&format!("\\u{:04x}", ch as u32)),
+			ch => ret.push(ch),
+		}
+	}
+
+	ret
+}
+
+/// Percent encode everything but the unreserved characters, so the value
+/// can be a single path segment or query parameter
+pub(crate) fn url_component(s: &str) -> String {
+	let mut ret = String::with_capacity(s.len());
+
+	for byte in s.bytes() {
+		match byte {
+			b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+				ret.push(byte as char)
+			}
+			byte => ret.push_str(&format!("%{:02X}", byte)),
+		}
+	}
+
+	ret
+}
+
+/// Quote the value as a single word for a POSIX shell. It's wrapped in single
+/// quotes, and every single quote inside is closed, escaped and reopened.
+pub(crate) fn shell(s: &str) -> String {
+	format!("'{}'", s.replace('\'', "'\\''"))
+}
@@ -0,0 +1,374 @@
+use core::fmt;
+use std::{borrow::Cow, cmp::Ordering, iter::Peekable, str::Chars};
+
+use crate::{scope::Scope, value::VariablePath, Value};
+
+/// A condition for `{%if}`.
+///
+/// Expressions compare variables and literals with `==`, `!=`, `<`, `<=`,
+/// `>` and `>=`, and combine conditions with `and`, `or` and `not`.
+/// Parentheses group. Literals are double quoted strings, numbers, `true`,
+/// `false` and `null`. Anything else is a variable path.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Expression {
+	Literal(Value),
+	Variable(VariablePath),
+	Not(Box<Expression>),
+	And(Box<Expression>, Box<Expression>),
+	Or(Box<Expression>, Box<Expression>),
+	Compare {
+		left: Box<Expression>,
+		comparison: Comparison,
+		right: Box<Expression>,
+	},
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Comparison {
+	Equal,
+	NotEqual,
+	Less,
+	LessEqual,
+	Greater,
+	GreaterEqual,
+}
+
+impl Expression {
+	/// Parse an expression, returning `None` if it isn't valid.
+	pub fn parse<S: AsRef<str>>(s: S) -> Option<Self> {
+		let lexemes = lex(s.as_ref())?;
+		let mut parser = Parser {
+			lexemes: lexemes.into_iter().peekable(),
+		};
+
+		let expr = parser.or()?;
+		match parser.lexemes.next() {
+			None => Some(expr),
+			Some(_) => None,
+		}
+	}
+
+	/// Evaluate the expression. Variables that are not set are null.
+	pub(crate) fn evaluate<'s>(&'s self, scope: &'s Scope) -> Cow<'s, Value> {
+		match self {
+			Expression::Literal(value) => Cow::Borrowed(value),
+			Expression::Variable(path) => scope.lookup(path).unwrap_or(Cow::Owned(Value::Null)),
+			Expression::Not(expr) => Cow::Owned(Value::Bool(!expr.is_true(scope))),
+			Expression::And(left, right) => {
+				Cow::Owned(Value::Bool(left.is_true(scope) && right.is_true(scope)))
+			}
+			Expression::Or(left, right) => {
+				Cow::Owned(Value::Bool(left.is_true(scope) || right.is_true(scope)))
+			}
+			Expression::Compare {
+				left,
+				comparison,
+				right,
+			} => {
+				let ordering = compare(&left.evaluate(scope), &right.evaluate(scope));
+
+				let result = match comparison {
+					Comparison::Equal => ordering == Some(Ordering::Equal),
+					Comparison::NotEqual => ordering != Some(Ordering::Equal),
+					Comparison::Less => ordering == Some(Ordering::Less),
+					Comparison::LessEqual => {
+						matches!(ordering, Some(Ordering::Less | Ordering::Equal))
+					}
+					Comparison::Greater => ordering == Some(Ordering::Greater),
+					Comparison::GreaterEqual => {
+						matches!(ordering, Some(Ordering::Greater | Ordering::Equal))
+					}
+				};
+
+				Cow::Owned(Value::Bool(result))
+			}
+		}
+	}
+
+	/// Evaluate the expression as a condition. This is [Value::is_truthy],
+	/// except that the string `false`, which is what [Document::set] stores
+	/// for `false`, is false too.
+	///
+	/// [Document::set]: crate::Document::set
+	pub(crate) fn is_true(&self, scope: &Scope) -> bool {
+		let value = self.evaluate(scope);
+		boolean(&value).unwrap_or_else(|| value.is_truthy())
+	}
+}
+
+fn number(value: &Value) -> Option<f64> {
+	match value {
+		Value::String(s) | Value::Safe(s) => s.trim().parse().ok(),
+		_ => value.as_f64(),
+	}
+}
+
+fn boolean(value: &Value) -> Option<bool> {
+	match value {
+		Value::Bool(b) => Some(*b),
+		Value::String(s) | Value::Safe(s) => s.trim().parse().ok(),
+		_ => None,
+	}
+}
+
+/// Compare two values. Numbers compare by value, and so do booleans, even
+/// when they're strings as they would be after [Document::set]. Other
+/// strings compare lexicographically. Values of other types are only ever
+/// equal to something exactly the same.
+///
+/// [Document::set]: crate::Document::set
+fn compare(left: &Value, right: &Value) -> Option<Ordering> {
+	match (left, right) {
+		(Value::String(l) | Value::Safe(l), Value::String(r) | Value::Safe(r)) => {
+			match (number(left), number(right)) {
+				(Some(l), Some(r)) => l.partial_cmp(&r),
+				_ => Some(l.cmp(r)),
+			}
+		}
+		(Value::Bool(_), Value::String(_) | Value::Safe(_))
+		| (Value::String(_) | Value::Safe(_), Value::Bool(_)) => {
+			Some(boolean(left)?.cmp(&boolean(right)?))
+		}
+		(Value::Integer(l), Value::Integer(r)) => Some(l.cmp(r)),
+		(Value::Integer(_) | Value::Float(_), _) | (_, Value::Integer(_) | Value::Float(_)) => {
+			number(left)?.partial_cmp(&number(right)?)
+		}
+		_ if left == right => Some(Ordering::Equal),
+		_ => None,
+	}
+}
+
+impl fmt::Display for Expression {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Expression::Literal(Value::String(s)) => write!(f, "{:?}", s),
+			Expression::Literal(Value::Null) => write!(f, "null"),
+			Expression::Literal(value) => write!(f, "{}", value),
+			Expression::Variable(path) => write!(f, "{}", path),
+			Expression::Not(expr) => write!(f, "not {}", expr),
+			Expression::And(left, right) => write!(f, "({} and {})", left, right),
+			Expression::Or(left, right) => write!(f, "({} or {})", left, right),
+			Expression::Compare {
+				left,
+				comparison,
+				right,
+			} => write!(f, "{} {} {}", left, comparison, right),
+		}
+	}
+}
+
+impl fmt::Display for Comparison {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let op = match self {
+			Comparison::Equal => "==",
+			Comparison::NotEqual => "!=",
+			Comparison::Less => "<",
+			Comparison::LessEqual => "<=",
+			Comparison::Greater => ">",
+			Comparison::GreaterEqual => ">=",
+		};
+
+		write!(f, "{}", op)
+	}
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Lexeme {
+	OpenParen,
+	CloseParen,
+	Compare(Comparison),
+	And,
+	Or,
+	Not,
+	Literal(Value),
+	Path(VariablePath),
+}
+
+fn lex(s: &str) -> Option<Vec<Lexeme>> {
+	let mut chars = s.chars().peekable();
+	let mut lexemes = vec![];
+
+	while let Some(ch) = chars.peek().copied() {
+		let lexeme = match ch {
+			ch if ch.is_whitespace() => {
+				chars.next();
+				continue;
+			}
+			'(' => {
+				chars.next();
+				Lexeme::OpenParen
+			}
+			')' => {
+				chars.next();
+				Lexeme::CloseParen
+			}
+			'=' | '!' | '<' | '>' => {
+				chars.next();
+				let equals = chars.next_if_eq(&'=').is_some();
+
+				Lexeme::Compare(match (ch, equals) {
+					('=', true) => Comparison::Equal,
+					('!', true) => Comparison::NotEqual,
+					('<', false) => Comparison::Less,
+					('<', true) => Comparison::LessEqual,
+					('>', false) => Comparison::Greater,
+					('>', true) => Comparison::GreaterEqual,
+					_ => return None,
+				})
+			}
+			'"' => Lexeme::Literal(Value::String(lex_string(&mut chars)?)),
+			_ => {
+				let mut word = String::new();
+				while let Some(ch) = chars.next_if(|ch| !is_delimiter(*ch)) {
+					word.push(ch);
+				}
+
+				match word.as_str() {
+					"and" => Lexeme::And,
+					"or" => Lexeme::Or,
+					"not" => Lexeme::Not,
+					_ => match parse_word(&word) {
+						Some(value) => Lexeme::Literal(value),
+						None => Lexeme::Path(VariablePath::parse(&word)?),
+					},
+				}
+			}
+		};
+
+		lexemes.push(lexeme);
+	}
+
+	Some(lexemes)
+}
+
+fn is_delimiter(ch: char) -> bool {
+	ch.is_whitespace() || matches!(ch, '(' | ')' | '=' | '!' | '<' | '>' | '"')
+}
+
+/// Parse an unquoted literal: a number, `true`, `false` or `null`.
+pub(crate) fn parse_word(word: &str) -> Option<Value> {
+	match word {
+		"true" => Some(Value::Bool(true)),
+		"false" => Some(Value::Bool(false)),
+		"null" => Some(Value::Null),
+		_ if word.starts_with(|ch: char| ch.is_ascii_digit() || ch == '-') => {
+			match word.parse::<i64>() {
+				Ok(i) => Some(Value::Integer(i)),
+				Err(_) => word.parse::<f64>().ok().map(Value::Float),
+			}
+		}
+		_ => None,
+	}
+}
+
+/// Parse a named argument like `title="Hello"`, `body=summary` or
+/// `href={url}`. The value is an expression, and can be in braces.
+pub(crate) fn parse_named_argument(s: &str) -> Option<(String, Expression)> {
+	let (name, value) = s.split_once('=')?;
+	if name.is_empty() || name.contains(|ch: char| ch.is_whitespace() || ch == '.') {
+		return None;
+	}
+
+	let value = match value.strip_prefix('{') {
+		Some(braced) => braced.strip_suffix('}')?,
+		None => value,
+	};
+
+	Some((name.to_owned(), Expression::parse(value)?))
+}
+
+/// Take a double quoted string from the front of `chars`. A backslash
+/// escapes the next character, and `\n` and `\t` are a newline and a tab.
+pub(crate) fn lex_string(chars: &mut Peekable<Chars>) -> Option<String> {
+	chars.next_if_eq(&'"')?;
+	let mut ret = String::new();
+
+	loop {
+		match chars.next()? {
+			'"' => break Some(ret),
+			'\\' => match chars.next()? {
+				'n' => ret.push('\n'),
+				't' => ret.push('\t'),
+				ch => ret.push(ch),
+			},
+			ch => ret.push(ch),
+		}
+	}
+}
+
+/// Parse a double quoted string like `", "`. Returns `None` if the string
+/// isn't quoted or there's anything after the closing quote.
+pub(crate) fn parse_string_literal(s: &str) -> Option<String> {
+	let mut chars = s.chars().peekable();
+	let ret = lex_string(&mut chars)?;
+
+	match chars.next() {
+		None => Some(ret),
+		Some(_) => None,
+	}
+}
+
+struct Parser {
+	lexemes: Peekable<std::vec::IntoIter<Lexeme>>,
+}
+
+impl Parser {
+	fn or(&mut self) -> Option<Expression> {
+		let mut expr = self.and()?;
+
+		while self.lexemes.next_if_eq(&Lexeme::Or).is_some() {
+			expr = Expression::Or(Box::new(expr), Box::new(self.and()?));
+		}
+
+		Some(expr)
+	}
+
+	fn and(&mut self) -> Option<Expression> {
+		let mut expr = self.not()?;
+
+		while self.lexemes.next_if_eq(&Lexeme::And).is_some() {
+			expr = Expression::And(Box::new(expr), Box::new(self.not()?));
+		}
+
+		Some(expr)
+	}
+
+	fn not(&mut self) -> Option<Expression> {
+		if self.lexemes.next_if_eq(&Lexeme::Not).is_some() {
+			Some(Expression::Not(Box::new(self.not()?)))
+		} else {
+			self.comparison()
+		}
+	}
+
+	fn comparison(&mut self) -> Option<Expression> {
+		let left = self.primary()?;
+
+		match self.lexemes.peek() {
+			Some(Lexeme::Compare(comparison)) => {
+				let comparison = *comparison;
+				self.lexemes.next();
+
+				Some(Expression::Compare {
+					left: Box::new(left),
+					comparison,
+					right: Box::new(self.primary()?),
+				})
+			}
+			_ => Some(left),
+		}
+	}
+
+	fn primary(&mut self) -> Option<Expression> {
+		match self.lexemes.next()? {
+			Lexeme::OpenParen => {
+				let expr = self.or()?;
+				self.lexemes.next_if_eq(&Lexeme::CloseParen)?;
+				Some(expr)
+			}
+			Lexeme::Literal(value) => Some(Expression::Literal(value)),
+			Lexeme::Path(path) => Some(Expression::Variable(path)),
+			_ => None,
+		}
+	}
+}
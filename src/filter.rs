@@ -0,0 +1,356 @@
+use core::fmt;
+use std::{collections::HashMap, error::Error, sync::Arc};
+
+use crate::{
+	escape::Escaper, expression::Expression, format::FormatSpec, scope::Scope, value::VariablePath,
+	Escape, Value,
+};
+
+/// A filter applied to a variable, like the `truncate:40` in
+/// `{title|truncate:40}`. Arguments follow a colon and are separated by
+/// commas. They can be literals or variables.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Filter {
+	pub name: String,
+	pub arguments: Vec<Expression>,
+}
+
+/// The built in filters
+///
+/// - `upper`, `lower`: change the case of every letter
+/// - `capitalize`: uppercase the first letter and lowercase the rest
+/// - `trim`: remove whitespace from the start and end
+/// - `truncate:length[,end]`: cut the value down to `length` characters and
+///   add `end`, `...` by default, if it was any longer
+/// - `replace:from,to`: replace every `from` with `to`
+/// - `default:value`: use `value` if the variable is unset like it would be
+///   for `{%if-set}`, so it's missing, null, `false`, the empty string or an
+///   empty list or map. `{name ?? value}` is the same as `{name|default:value}`.
+/// - `length`: the number of items in a list or map, or characters in a string
+/// - `json`: the value as JSON
+/// - `escape:name`: escape the value with the built in escaper `name`, like
+///   `url`, instead of the document's. See [Escape::from_name](crate::Escape::from_name).
+/// - `safe`: never escape the value. This should be the last filter.
+pub const BUILTIN_FILTERS: &[&str] = &[
+	"upper",
+	"lower",
+	"capitalize",
+	"trim",
+	"truncate",
+	"replace",
+	"default",
+	"length",
+	"json",
+	"escape",
+	"safe",
+];
+
+impl Filter {
+	/// Whether or not this filter should still run if the variable it's on
+	/// isn't set. Otherwise the variable is passed through like any other
+	/// unset variable.
+	pub fn applies_to_unset(&self) -> bool {
+		self.name == "default"
+	}
+
+	/// Apply the filter, using a custom filter if one with this name was
+	/// registered and a built in filter if not.
+	pub(crate) fn apply(
+		&self,
+		value: Value,
+		custom: &CustomFilters,
+		scope: &Scope,
+	) -> Result<Value, FilterError> {
+		let arguments: Vec<Value> = self
+			.arguments
+			.iter()
+			.map(|arg| arg.evaluate(scope).into_owned())
+			.collect();
+
+		match custom.get(&self.name) {
+			Some(func) => func(&value, &arguments),
+			None => apply_builtin(&self.name, value, &arguments),
+		}
+	}
+}
+
+/// The parts of a variable like `{title:<20|trim}`
+pub(crate) type ParsedVariable = (VariablePath, Option<FormatSpec>, Vec<Filter>);
+
+/// Parse the inside of a variable, like `title:<20|trim|truncate:40`, into
+/// the path, its format and its filters. Returns `None` if it's not a valid
+/// variable.
+pub(crate) fn parse_variable(s: &str) -> Option<ParsedVariable> {
+	// name ?? "default" is the same as name|default:"default"
+	let mut defaults = split_unquoted(s, "??").into_iter();
+	let s = defaults.next()?.trim();
+	let default_filters = defaults.map(|default| {
+		Some(Filter {
+			name: String::from("default"),
+			arguments: vec![Expression::parse(default.trim())?],
+		})
+	});
+
+	let mut parts = split_unquoted(s, "|").into_iter();
+	let first = parts.next()?;
+	let (path, format) = match first.split_once(':') {
+		None => (VariablePath::parse(first)?, None),
+		Some((path, format)) => (VariablePath::parse(path)?, Some(FormatSpec::parse(format)?)),
+	};
+
+	let filters = parts
+		.map(|part| {
+			let (name, arguments) = match part.split_once(':') {
+				None => (part, vec![]),
+				Some((name, args)) => (
+					name,
+					split_unquoted(args, ",")
+						.into_iter()
+						.map(Expression::parse)
+						.collect::<Option<Vec<_>>>()?,
+				),
+			};
+
+			if name.is_empty() || name.contains(char::is_whitespace) {
+				return None;
+			}
+
+			Some(Filter {
+				name: name.to_owned(),
+				arguments,
+			})
+		})
+		.chain(default_filters)
+		.collect::<Option<Vec<_>>>()?;
+
+	Some((path, format, filters))
+}
+
+/// Split on `delimiter` wherever it's not inside a double quoted string
+pub(crate) fn split_unquoted<'s>(s: &'s str, delimiter: &str) -> Vec<&'s str> {
+	let mut parts = vec![];
+	let mut start = 0;
+	let mut quoted = false;
+	let mut escaped = false;
+
+	for (idx, ch) in s.char_indices() {
+		match ch {
+			_ if idx < start => (),
+			_ if escaped => escaped = false,
+			'\\' if quoted => escaped = true,
+			'"' => quoted = !quoted,
+			_ if !quoted && s[idx..].starts_with(delimiter) => {
+				parts.push(&s[start..idx]);
+				start = idx + delimiter.len();
+			}
+			_ => (),
+		}
+	}
+
+	parts.push(&s[start..]);
+	parts
+}
+
+fn apply_builtin(name: &str, value: Value, args: &[Value]) -> Result<Value, FilterError> {
+	let expect_arguments = |min: usize, max: usize| {
+		if args.len() < min || args.len() > max {
+			Err(FilterError::new(format!(
+				"'{}' takes {} to {} arguments but was given {}",
+				name,
+				min,
+				max,
+				args.len()
+			)))
+		} else {
+			Ok(())
+		}
+	};
+
+	// Changing the text of a safe value keeps it safe
+	let safe = matches!(value, Value::Safe(_));
+	let text = |s: String| {
+		if safe {
+			Value::Safe(s)
+		} else {
+			Value::String(s)
+		}
+	};
+
+	match name {
+		"upper" => {
+			expect_arguments(0, 0)?;
+			Ok(text(value.to_string().to_uppercase()))
+		}
+		"lower" => {
+			expect_arguments(0, 0)?;
+			Ok(text(value.to_string().to_lowercase()))
+		}
+		"capitalize" => {
+			expect_arguments(0, 0)?;
+			let string = value.to_string();
+			let mut chars = string.chars();
+
+			Ok(text(match chars.next() {
+				None => String::new(),
+				Some(first) => first
+					.to_uppercase()
+					.chain(chars.as_str().to_lowercase().chars())
+					.collect(),
+			}))
+		}
+		"trim" => {
+			expect_arguments(0, 0)?;
+			Ok(text(value.to_string().trim().to_owned()))
+		}
+		"truncate" => {
+			expect_arguments(1, 2)?;
+			let length = args[0]
+				.as_i64()
+				.and_then(|len| usize::try_from(len).ok())
+				.ok_or_else(|| {
+					FilterError::new("'truncate' needs a length that is a positive integer")
+				})?;
+			let end = args.get(1).map(|end| end.to_string());
+
+			let string = value.to_string();
+			match string.char_indices().nth(length) {
+				None => Ok(text(string)),
+				Some((idx, _)) => Ok(text(format!(
+					"{}{}",
+					&string[..idx],
+					end.as_deref().unwrap_or("...")
+				))),
+			}
+		}
+		"replace" => {
+			expect_arguments(2, 2)?;
+			Ok(text(
+				value
+					.to_string()
+					.replace(&args[0].to_string(), &args[1].to_string()),
+			))
+		}
+		"default" => {
+			expect_arguments(1, 1)?;
+			// The same values {%if-set} counts as unset
+			if !value.is_truthy() {
+				Ok(args[0].clone())
+			} else {
+				Ok(value)
+			}
+		}
+		"length" => {
+			expect_arguments(0, 0)?;
+			match value {
+				Value::Null => Ok(Value::Integer(0)),
+				Value::String(s) | Value::Safe(s) => Ok(Value::from(s.chars().count())),
+				Value::List(l) => Ok(Value::from(l.len())),
+				Value::Map(m) => Ok(Value::from(m.len())),
+				_ => Err(FilterError::new(
+					"'length' only works on strings, lists and maps",
+				)),
+			}
+		}
+		"json" => {
+			expect_arguments(0, 0)?;
+			Ok(Value::String(value.to_json()))
+		}
+		"escape" => {
+			expect_arguments(1, 1)?;
+			let name = args[0].to_string();
+			let escape = Escape::from_name(&name)
+				.ok_or_else(|| FilterError::new(format!("'{}' is not an escaper", name)))?;
+
+			Ok(Value::Safe(escape.escape(&value.to_string())))
+		}
+		"safe" => {
+			expect_arguments(0, 0)?;
+			match value {
+				Value::Safe(s) => Ok(Value::Safe(s)),
+				value => Ok(Value::Safe(value.to_string())),
+			}
+		}
+		_ => Err(FilterError::new(format!("'{}' is not a filter", name))),
+	}
+}
+
+impl fmt::Display for Filter {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}", self.name)?;
+
+		for (idx, arg) in self.arguments.iter().enumerate() {
+			let prefix = if idx == 0 { ':' } else { ',' };
+			write!(f, "{}{}", prefix, arg)?;
+		}
+
+		Ok(())
+	}
+}
+
+/// The signature of a filter. It's given the value and the arguments.
+pub type FilterFn = dyn Fn(&Value, &[Value]) -> Result<Value, FilterError> + Send + Sync;
+
+/// Filters registered with [Options::filter](crate::Options::filter).
+#[derive(Clone, Default)]
+pub struct CustomFilters {
+	filters: HashMap<String, Arc<FilterFn>>,
+}
+
+impl CustomFilters {
+	pub fn insert<F>(&mut self, name: String, filter: F)
+	where
+		F: Fn(&Value, &[Value]) -> Result<Value, FilterError> + Send + Sync + 'static,
+	{
+		self.filters.insert(name, Arc::new(filter));
+	}
+
+	pub fn get(&self, name: &str) -> Option<&FilterFn> {
+		self.filters.get(name).map(|func| &**func)
+	}
+
+	pub fn contains(&self, name: &str) -> bool {
+		self.filters.contains_key(name)
+	}
+}
+
+impl fmt::Debug for CustomFilters {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_set().entries(self.filters.keys()).finish()
+	}
+}
+
+impl PartialEq for CustomFilters {
+	/// Filters are equal if they have the same names and are the very same
+	/// functions.
+	fn eq(&self, other: &Self) -> bool {
+		self.filters.len() == other.filters.len()
+			&& self.filters.iter().all(|(name, func)| {
+				other
+					.filters
+					.get(name)
+					.map(|other| Arc::ptr_eq(func, other))
+					.unwrap_or(false)
+			})
+	}
+}
+
+/// The reason a filter could not be applied to a value
+#[derive(Clone, Debug, PartialEq)]
+pub struct FilterError {
+	message: String,
+}
+
+impl FilterError {
+	pub fn new<S: Into<String>>(message: S) -> Self {
+		Self {
+			message: message.into(),
+		}
+	}
+}
+
+impl Error for FilterError {}
+impl fmt::Display for FilterError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}", self.message)
+	}
+}
@@ -0,0 +1,289 @@
+use core::fmt;
+
+use crate::Value;
+
+/// A format specifier on a variable, like the `>10.2` in `{amount:>10.2}`.
+///
+/// These mirror [std::fmt]: `[[fill]align][sign]['#']['0'][width]['.' precision][type]`
+/// - `align` is `<`, `^` or `>` for left, center and right. Numbers are right
+///   aligned by default and everything else left aligned.
+/// - `sign` is `+` to always write the sign of a number
+/// - `#` adds a `0x`, `0o` or `0b` prefix to hex, octal and binary numbers
+/// - `0` pads numbers with zeros after the sign
+/// - `precision` is the number of decimal places for numbers, and the most
+///   characters to write for anything else
+/// - `type` is `x` or `X` for hex, `o` for octal, `b` for binary, and `e` or
+///   `E` for scientific notation. Numbers that aren't integers ignore the
+///   first four.
+///
+/// Strings that are numbers, like those set with [Document::set], are
+/// formatted as numbers if the specifier only makes sense for numbers, like
+/// one with a precision or a sign.
+///
+/// [Document::set]: crate::Document::set
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FormatSpec {
+	pub fill: char,
+	pub align: Option<Align>,
+	pub sign: bool,
+	pub alternate: bool,
+	pub zero: bool,
+	pub width: Option<usize>,
+	pub precision: Option<usize>,
+	pub kind: FormatKind,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Align {
+	Left,
+	Center,
+	Right,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FormatKind {
+	Display,
+	LowerHex,
+	UpperHex,
+	Octal,
+	Binary,
+	LowerExp,
+	UpperExp,
+}
+
+impl FormatSpec {
+	/// Parse a format specifier, without the leading colon. Returns `None` if
+	/// it isn't valid.
+	pub fn parse<S: AsRef<str>>(s: S) -> Option<Self> {
+		let s = s.as_ref();
+		let mut spec = FormatSpec {
+			fill: ' ',
+			align: None,
+			sign: false,
+			alternate: false,
+			zero: false,
+			width: None,
+			precision: None,
+			kind: FormatKind::Display,
+		};
+
+		let mut chars = s.chars().peekable();
+
+		// The fill is only a fill if it's followed by an alignment
+		let mut lookahead = chars.clone();
+		match (lookahead.next(), lookahead.next().and_then(align)) {
+			(Some(fill), Some(align)) => {
+				spec.fill = fill;
+				spec.align = Some(align);
+				chars.nth(1);
+			}
+			_ => {
+				if let Some(align) = chars.peek().copied().and_then(align) {
+					spec.align = Some(align);
+					chars.next();
+				}
+			}
+		}
+
+		spec.sign = chars.next_if_eq(&'+').is_some();
+		spec.alternate = chars.next_if_eq(&'#').is_some();
+		spec.zero = chars.next_if_eq(&'0').is_some();
+		spec.width = take_number(&mut chars);
+
+		if chars.next_if_eq(&'.').is_some() {
+			spec.precision = Some(take_number(&mut chars)?);
+		}
+
+		spec.kind = match chars.next() {
+			None => FormatKind::Display,
+			Some('x') => FormatKind::LowerHex,
+			Some('X') => FormatKind::UpperHex,
+			Some('o') => FormatKind::Octal,
+			Some('b') => FormatKind::Binary,
+			Some('e') => FormatKind::LowerExp,
+			Some('E') => FormatKind::UpperExp,
+			Some(_) => return None,
+		};
+
+		match chars.next() {
+			None => Some(spec),
+			Some(_) => None,
+		}
+	}
+
+	fn is_numeric(&self) -> bool {
+		self.sign || self.zero || self.precision.is_some() || self.kind != FormatKind::Display
+	}
+
+	/// Format a value according to this specifier
+	pub fn format(&self, value: &Value) -> String {
+		let number = match value {
+			Value::Integer(_) | Value::Float(_) => Some(value.clone()),
+			Value::String(s) | Value::Safe(s) if self.is_numeric() => {
+				crate::expression::parse_word(s.trim()).filter(|v| v.as_f64().is_some())
+			}
+			_ => None,
+		};
+
+		match number {
+			Some(number) => self.format_number(&number),
+			None => {
+				let string = value.to_string();
+				let string = match self.precision {
+					Some(precision) => string.chars().take(precision).collect(),
+					None => string,
+				};
+
+				self.pad(String::new(), string, Align::Left, false)
+			}
+		}
+	}
+
+	fn format_number(&self, number: &Value) -> String {
+		// Only integers can be written in another radix, so anything else
+		// ignores the type like a string does
+		let kind = match (number, self.kind) {
+			(Value::Integer(_), kind) => kind,
+			(_, FormatKind::LowerHex | FormatKind::UpperHex)
+			| (_, FormatKind::Octal | FormatKind::Binary) => FormatKind::Display,
+			(_, kind) => kind,
+		};
+
+		let (negative, digits) = match (number, kind) {
+			// An integer with a precision falls through and is written as a float
+			(Value::Integer(i), FormatKind::Display) if self.precision.is_none() => {
+				(*i < 0, i.unsigned_abs().to_string())
+			}
+			(Value::Integer(i), FormatKind::LowerHex) => {
+				(*i < 0, format!("{:x}", i.unsigned_abs()))
+			}
+			(Value::Integer(i), FormatKind::UpperHex) => {
+				(*i < 0, format!("{:X}", i.unsigned_abs()))
+			}
+			(Value::Integer(i), FormatKind::Octal) => (*i < 0, format!("{:o}", i.unsigned_abs())),
+			(Value::Integer(i), FormatKind::Binary) => (*i < 0, format!("{:b}", i.unsigned_abs())),
+			(number, kind) => {
+				let float = number.as_f64().unwrap_or_default();
+				let abs = float.abs();
+
+				let digits = match (kind, self.precision) {
+					(FormatKind::LowerExp, Some(p)) => format!("{:.*e}", p, abs),
+					(FormatKind::LowerExp, None) => format!("{:e}", abs),
+					(FormatKind::UpperExp, Some(p)) => format!("{:.*E}", p, abs),
+					(FormatKind::UpperExp, None) => format!("{:E}", abs),
+					(_, Some(p)) => format!("{:.*}", p, abs),
+					(_, None) => match number {
+						Value::Integer(i) => i.unsigned_abs().to_string(),
+						_ => abs.to_string(),
+					},
+				};
+
+				(float.is_sign_negative() && !float.is_nan(), digits)
+			}
+		};
+
+		let mut prefix = match (negative, self.sign) {
+			(true, _) => String::from("-"),
+			(false, true) => String::from("+"),
+			(false, false) => String::new(),
+		};
+
+		if self.alternate {
+			prefix.push_str(match kind {
+				FormatKind::LowerHex | FormatKind::UpperHex => "0x",
+				FormatKind::Octal => "0o",
+				FormatKind::Binary => "0b",
+				_ => "",
+			});
+		}
+
+		self.pad(prefix, digits, Align::Right, self.zero)
+	}
+
+	/// Pad the prefix and body out to the width. Zero padding goes between the
+	/// prefix and the body, and ignores the fill and alignment like it does in
+	/// [std::fmt].
+	fn pad(&self, prefix: String, body: String, default_align: Align, zero: bool) -> String {
+		let len = prefix.chars().count() + body.chars().count();
+		let padding = self.width.unwrap_or(0).saturating_sub(len);
+
+		if zero {
+			return format!("{}{}{}", prefix, "0".repeat(padding), body);
+		}
+
+		let fill = |count: usize| self.fill.to_string().repeat(count);
+		match self.align.unwrap_or(default_align) {
+			Align::Left => format!("{}{}{}", prefix, body, fill(padding)),
+			Align::Right => format!("{}{}{}", fill(padding), prefix, body),
+			Align::Center => format!(
+				"{}{}{}{}",
+				fill(padding / 2),
+				prefix,
+				body,
+				fill(padding - padding / 2)
+			),
+		}
+	}
+}
+
+fn align(ch: char) -> Option<Align> {
+	match ch {
+		'<' => Some(Align::Left),
+		'^' => Some(Align::Center),
+		'>' => Some(Align::Right),
+		_ => None,
+	}
+}
+
+fn take_number(chars: &mut std::iter::Peekable<std::str::Chars>) -> Option<usize> {
+	let mut digits = String::new();
+	while let Some(digit) = chars.next_if(char::is_ascii_digit) {
+		digits.push(digit);
+	}
+
+	digits.parse().ok()
+}
+
+impl fmt::Display for FormatSpec {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		if let Some(align) = self.align {
+			if self.fill != ' ' {
+				write!(f, "{}", self.fill)?;
+			}
+
+			let align = match align {
+				Align::Left => '<',
+				Align::Center => '^',
+				Align::Right => '>',
+			};
+			write!(f, "{}", align)?;
+		}
+
+		if self.sign {
+			write!(f, "+")?;
+		}
+		if self.alternate {
+			write!(f, "#")?;
+		}
+		if self.zero {
+			write!(f, "0")?;
+		}
+		if let Some(width) = self.width {
+			write!(f, "{}", width)?;
+		}
+		if let Some(precision) = self.precision {
+			write!(f, ".{}", precision)?;
+		}
+
+		let kind = match self.kind {
+			FormatKind::Display => "",
+			FormatKind::LowerHex => "x",
+			FormatKind::UpperHex => "X",
+			FormatKind::Octal => "o",
+			FormatKind::Binary => "b",
+			FormatKind::LowerExp => "e",
+			FormatKind::UpperExp => "E",
+		};
+		write!(f, "{}", kind)
+	}
+}
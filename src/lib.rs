@@ -3,6 +3,94 @@
 //!
 //! You can prevent `{word}` from being seen as a variable by escaping the opening brace. Like `\{this}`.
 //!
+//! Variables hold a [Value]. Anything that implements `Display` can be set with [Document::set],
+//! but [Document::set_value] keeps numbers, booleans, lists and maps intact until the document
+//! is compiled.
+//!
+//! Nested maps and lists are reached with dots. If `user` is a map, `{user.name}` is its `name`
+//! and `{order.items.0.sku}` is the `sku` of the first item in the list `order.items`.
+//!
+//! Filters change how a variable is written and are applied left to right, like
+//! `{title|trim|truncate:40}`. Arguments follow a colon, are separated by commas, and can be
+//! literals or other variables. The built in filters are `upper`, `lower`, `capitalize`, `trim`,
+//! `truncate:length[,end]`, `replace:from,to`, `default:value`, `length`, `json` and `safe`.
+//! Register your own with [Options::filter].
+//!
+//! `{name ?? "friend"}` is short for `{name|default:"friend"}`. Either one is used when the
+//! variable would be unset for `{%if-set}`, like when it's missing, the empty string, `false`
+//! or an empty list, and the default can be another variable.
+//!
+//! A format after the name pads, aligns and rounds the value like [std::fmt] does. `{name:<20}`
+//! is left aligned in 20 characters, `{amount:>10.2}` has two decimal places and is right aligned
+//! in 10, and `{id:08}` is padded with zeros. See [FormatSpec] for everything it can do. The
+//! format is applied after the filters, as in `{title:^30|upper}`.
+//!
+//! Templates read from `.html` files escape every variable for HTML, and other extensions like
+//! `.xml`, `.json` and `.sh` have escapers too. Set [Options::escape] to choose for yourself,
+//! or to use your own [Escaper]. Escape one variable differently with a filter like
+//! `{query|escape:"url"}`. A variable set with [Document::set_raw], or ending with the `safe`
+//! filter like `{body|safe}`, is never escaped.
+//!
+//! ### Commands
+//! Commands are surrounded by `{%` and `}`. Those that have a body, like `{%if-set}`, are closed
+//! with `{%end}`.
+//!
+//! `{%if-set name}...{%else}...{%end}` uses its body if the variable `name` is set and not
+//! empty, and the optional else branch otherwise. `{%if-unset name}`, or `{%unless name}`, is
+//! the opposite.
+//!
+//! `{%if condition}` takes a small expression. Compare variables and literals with `==`, `!=`,
+//! `<`, `<=`, `>` and `>=`, combine them with `and`, `or` and `not`, and group with parentheses.
+//! Literals are double quoted strings, numbers, `true`, `false` and `null`. For example
+//! `{%if status == "paid" and not (total > 100)}`. Variables set with [Document::set] that are
+//! numbers or `true` and `false` compare and test as numbers and booleans.
+//!
+//! Conditionals can be chained with `{%else-if}`, `{%else-if-set}`, `{%else-if-unset}` and
+//! `{%else-if-pattern}` and closed with a single `{%end}`.
+//!
+//! `{%for item in items}...{%end}` repeats its body for every value in the list `items`. The
+//! current value is available as `{item}`, and dotted paths like `{item.name}` work on it.
+//!
+//! Inside a `{%for}` and every instance of a pattern there is also a `loop` variable with
+//! - `loop.index`, the iteration counting from 1
+//! - `loop.index0`, the iteration counting from 0
+//! - `loop.first` and `loop.last`, true on the first and last iterations
+//! - `loop.length`, how many iterations there are in total
+//!
+//! `{%pattern name}...{%end}` marks where instances of a pattern go. They're filled from Rust
+//! with [Document::get_pattern] and [Document::set_pattern]. Give it a separator with
+//! `{%pattern name sep=", "}` and use `{%empty}` to start a branch that's used when no
+//! instances were set.
+//!
+//! Patterns can be inside other commands, including other patterns. If two nested patterns
+//! share a name, get the one you want with a path like `table/row`.
+//!
+//! `{%if-pattern name}...{%else}...{%end}` checks whether any instances of a pattern were set,
+//! and `{patterns.name}` is how many there are.
+//!
+//! A pattern can contain itself with `{%children name}`, which is where the children of each
+//! instance go. Build the tree with [Pattern::child] and [Pattern::add_child].
+//!
+//! `{%include path}` puts another template in place of the command. The path can have variables
+//! in it, like `{%include themes/{theme}/header.bpl}`. If they're set by a `{%set}` before the
+//! include, or with [Options::variable], the file is included when the document is parsed.
+//! Otherwise it's included when the document is compiled. Set [Options::lazy_includes] to
+//! include every file when the document is compiled, and only if it's used.
+//!
+//! Set variables for an include with `{%include card.bpl title="Hello" body=summary}`. The
+//! values are expressions like those in `{%if}` and can be written in braces, like
+//! `title={heading}`. Add `only` to the end to hide every other variable from the include.
+//!
+//! Macros are reusable pieces of a template. Define one at the top level of a template with
+//! `{%macro button label href kind="primary"}...{%end}`, giving each parameter an optional
+//! default, and use it with `{%call button label="Buy" href={buy_url}}`. Parameters that aren't
+//! given and have no default are unset. `{%import macros.bpl}` brings in the macros from
+//! another template without writing anything from it.
+//!
+//! `{%import components.bpl as ui}` keeps the macros and patterns of another template under a
+//! name instead. Call its macros like `{%call ui.card}` and get its patterns with
+//! `get_pattern("ui.card")`. Place their instances with an empty `{%pattern ui.card}{%end}`.
+//!
 //! ## Example
 //! If you have this document in something like `template.bpl`
 //! ```text
@@ -31,13 +119,37 @@
 //! }
 //! ```
 
+// The examples are indented with tabs like the rest of the crate
+#![allow(clippy::tabs_in_doc_comments)]
+
 mod document;
+mod escape;
+mod expression;
+mod filter;
+mod format;
 pub mod options;
+mod scope;
+mod value;
 
 pub use document::Document;
 pub use document::ParseError;
+pub use document::Pattern;
+pub use document::PatternError;
 pub use document::Token;
+pub use escape::Escaper;
+pub use expression::Comparison;
+pub use expression::Expression;
+pub use filter::CustomFilters;
+pub use filter::Filter;
+pub use filter::FilterError;
+pub use filter::FilterFn;
+pub use format::Align;
+pub use format::FormatKind;
+pub use format::FormatSpec;
+pub use options::Escape;
 pub use options::Options;
+pub use value::Value;
+pub use value::VariablePath;
 
 #[macro_export]
 macro_rules! variables {
@@ -63,7 +175,19 @@ mod test {
 	use crate::options::IncludeMethod;
 
 	use super::*;
-	use std::path::PathBuf;
+	use std::{collections::HashMap, path::PathBuf};
+
+	fn path(s: &str) -> VariablePath {
+		VariablePath::parse(s).unwrap()
+	}
+
+	fn var(s: &str) -> Token {
+		Token::Variable {
+			path: path(s),
+			format: None,
+			filters: vec![],
+		}
+	}
 
 	#[test]
 	fn compile_all_set() {
@@ -124,12 +248,7 @@ mod test {
 	#[test]
 	fn only_variable() {
 		let doc = Document::from_str("{variable}", Options::default()).unwrap();
-		assert_eq!(
-			doc.tokens,
-			vec![Token::Variable {
-				name: String::from("variable")
-			}]
-		);
+		assert_eq!(doc.tokens, vec![var("variable")]);
 	}
 
 	#[test]
@@ -139,9 +258,7 @@ mod test {
 			doc.tokens,
 			vec![
 				Token::Text(String::from("Hello ")),
-				Token::Variable {
-					name: String::from("name")
-				},
+				var("name"),
 				Token::Text(String::from(", how are you?"))
 			]
 		);
@@ -152,12 +269,7 @@ mod test {
 		let doc = Document::from_str("Hello {name}", Options::default()).unwrap();
 		assert_eq!(
 			doc.tokens,
-			vec![
-				Token::Text(String::from("Hello ")),
-				Token::Variable {
-					name: String::from("name")
-				}
-			]
+			vec![Token::Text(String::from("Hello ")), var("name")]
 		);
 	}
 
@@ -166,12 +278,7 @@ mod test {
 		let doc = Document::from_str("{name}, hello!", Options::default()).unwrap();
 		assert_eq!(
 			doc.tokens,
-			vec![
-				Token::Variable {
-					name: String::from("name")
-				},
-				Token::Text(String::from(", hello!"))
-			]
+			vec![var("name"), Token::Text(String::from(", hello!"))]
 		);
 	}
 
@@ -186,18 +293,398 @@ mod test {
 			doc.tokens,
 			vec![
 				Token::Text(String::from("The weather is ")),
-				Token::Variable {
-					name: String::from("weather")
-				},
+				var("weather"),
 				Token::Text(String::from(" in ")),
-				Token::Variable {
-					name: String::from("location")
-				},
+				var("location"),
 				Token::Text(String::from(" today."))
 			]
 		);
 	}
 
+	#[test]
+	fn dotted_variable() {
+		let doc = Document::from_str("{order.items.0.sku}", Options::default()).unwrap();
+		assert_eq!(doc.tokens, vec![var("order.items.0.sku")]);
+	}
+
+	#[test]
+	fn invalid_dotted_variable() {
+		let doc = Document::from_str("{order..sku}", Options::default()).unwrap();
+		assert_eq!(doc.tokens, vec![Token::Text("{order..sku}".into())]);
+	}
+
+	#[test]
+	fn compile_dotted_variables() {
+		let mut doc = Document::from_str(
+			"{user.name} ordered {order.items.0.sku} and {order.items.1.sku}{order.items.2.sku}",
+			Options::default(),
+		)
+		.unwrap();
+
+		let mut user = HashMap::new();
+		user.insert("name", "Ferris");
+		doc.set_value("user", user);
+
+		let items: Vec<Value> = ["crab-1", "crab-2"]
+			.into_iter()
+			.map(|sku| {
+				let mut item = HashMap::new();
+				item.insert("sku", sku);
+				Value::from(item)
+			})
+			.collect();
+		let mut order = HashMap::new();
+		order.insert("items", items);
+		doc.set_value("order", order);
+
+		assert_eq!(
+			doc.compile(),
+			"Ferris ordered crab-1 and crab-2{order.items.2.sku}"
+		)
+	}
+
+	#[test]
+	fn dotted_variable_flat_name() {
+		let mut doc = Document::from_str("{user.name}", Options::default()).unwrap();
+		doc.set("user.name", "Ferris");
+
+		assert_eq!(doc.compile(), "Ferris")
+	}
+
+	#[test]
+	fn filter_parse() {
+		let doc = Document::from_str(
+			"{title|trim|truncate:40,\" (more)\"|replace:\"a|b\",other}",
+			Options::default(),
+		)
+		.unwrap();
+
+		assert_eq!(
+			doc.tokens,
+			vec![Token::Variable {
+				path: path("title"),
+				format: None,
+				filters: vec![
+					Filter {
+						name: "trim".into(),
+						arguments: vec![]
+					},
+					Filter {
+						name: "truncate".into(),
+						arguments: vec![
+							Expression::Literal(Value::from(40)),
+							Expression::Literal(Value::from(" (more)"))
+						]
+					},
+					Filter {
+						name: "replace".into(),
+						arguments: vec![
+							Expression::Literal(Value::from("a|b")),
+							Expression::Variable(path("other"))
+						]
+					}
+				]
+			}]
+		)
+	}
+
+	#[test]
+	fn filter_unknown() {
+		let mut doc = Document::from_str("{name|shout}", Options::default()).unwrap();
+		doc.set("name", "Ferris");
+		assert_eq!(doc.compile(), "{name|shout}")
+	}
+
+	#[test]
+	fn filter_unknown_is_text() {
+		let doc = Document::from_str("x {a|b} y", Options::default()).unwrap();
+		assert_eq!(doc.compile(), "x {a|b} y");
+
+		let doc = Document::from_str("<script>if(x){y|=1}</script>", Options::default()).unwrap();
+		assert_eq!(doc.compile(), "<script>if(x){y|=1}</script>")
+	}
+
+	#[test]
+	fn filter_builtins() {
+		let mut doc = Document::from_str(
+			"{name|upper} {name|lower} {shout|capitalize} [{padded|trim}] {title|truncate:5} {title|truncate:5,\"~\"} {title|replace:\" \",\"-\"} {list|length} {title|length} {list|json}",
+			Options::default(),
+		)
+		.unwrap();
+		doc.set("name", "Ferris");
+		doc.set("shout", "hELLO");
+		doc.set("padded", "  space  ");
+		doc.set("title", "A long title");
+		doc.set_value(
+			"list",
+			vec![Value::from("a\"b"), Value::from(1), Value::Null],
+		);
+
+		assert_eq!(
+			doc.compile(),
+			"FERRIS ferris Hello [space] A lon... A lon~ A-long-title 3 12 [\"a\\\"b\",1,null]"
+		)
+	}
+
+	#[test]
+	fn filter_default() {
+		let mut doc = Document::from_str(
+			"{price|default:\"free\"} {name|default:fallback|upper} {unset|upper} {set|default:\"nope\"}",
+			Options::default(),
+		)
+		.unwrap();
+		doc.set("fallback", "friend");
+		doc.set("set", "yes");
+
+		assert_eq!(doc.compile(), "free FRIEND {unset|upper} yes")
+	}
+
+	#[test]
+	fn inline_default() {
+		let mut doc = Document::from_str(
+			"Hi {name ?? \"friend\"}! {empty??\"none\"} {title|upper ?? \"untitled\"} {a ?? b ?? \"c\"} {empty|default:\"blank\"} {q ?? \"??\"}",
+			Options::default(),
+		)
+		.unwrap();
+		doc.set("empty", "");
+		doc.set("title", "");
+
+		assert_eq!(doc.compile(), "Hi friend! none untitled c blank ??");
+
+		let mut doc =
+			Document::from_str("{name ?? fallback} {bad ??}", Options::default()).unwrap();
+		doc.set("name", "Ferris");
+
+		assert_eq!(doc.compile(), "Ferris {bad ??}")
+	}
+
+	#[test]
+	fn inline_default_mirrors_if_set() {
+		let mut doc = Document::from_str(
+			"{flag ?? \"d\"} {list ?? \"none\"} {zero ?? \"d\"} {%if-set flag}set{%else}unset{%end}",
+			Options::default(),
+		)
+		.unwrap();
+		doc.set_value("flag", false);
+		doc.set_value("list", Vec::<Value>::new());
+		doc.set_value("zero", 0);
+
+		assert_eq!(doc.compile(), "d none 0 unset")
+	}
+
+	#[test]
+	fn filter_failure_passes_through() {
+		let mut doc =
+			Document::from_str("{number|length} {name|truncate:\"x\"}", Options::default())
+				.unwrap();
+		doc.set_value("number", 12);
+		doc.set("name", "Ferris");
+
+		assert_eq!(doc.compile(), "{number|length} {name|truncate:\"x\"}")
+	}
+
+	#[test]
+	fn filter_custom() {
+		let options = Options::default()
+			.filter("product_url", |value, args| {
+				let base = args.first().map(|v| v.to_string()).unwrap_or_default();
+				Ok(Value::String(format!("{}/products/{}", base, value)))
+			})
+			.filter("upper", |_, _| Ok(Value::from("overridden")))
+			.filter("fails", |_, _| Err(FilterError::new("always")));
+
+		let mut doc = Document::from_str(
+			"{sku|product_url:\"https://shop\"} {sku|upper} {sku|fails}",
+			options,
+		)
+		.unwrap();
+		doc.set("sku", "crab-1");
+
+		assert_eq!(
+			doc.compile(),
+			"https://shop/products/crab-1 overridden {sku|fails}"
+		)
+	}
+
+	#[test]
+	fn format_spec() {
+		let mut doc = Document::from_str(
+			"[{name:<8}] [{amount:>10.2}] [{id:08}] [{name:*^9}] [{neg:+06.1}] [{id:#x}] [{text:.3}]",
+			Options::default(),
+		)
+		.unwrap();
+		doc.set("name", "Ferris");
+		doc.set("amount", 12.5);
+		doc.set_value("id", 255);
+		doc.set_value("neg", -3.25);
+		doc.set("text", "abcdef");
+
+		assert_eq!(
+			doc.compile(),
+			"[Ferris  ] [     12.50] [00000255] [*Ferris**] [-003.2] [0xff] [abc]"
+		)
+	}
+
+	#[test]
+	fn format_spec_precision_on_integer() {
+		let mut doc = Document::from_str(
+			"[{amount:.2}] [{count:>8.2}] [{count:.0}]",
+			Options::default(),
+		)
+		.unwrap();
+		doc.set("amount", 5.0);
+		doc.set_value("count", 5);
+
+		assert_eq!(doc.compile(), "[5.00] [    5.00] [5]")
+	}
+
+	#[test]
+	fn format_spec_floats() {
+		let mut doc = Document::from_str(
+			"[{f:#x}] [{f:b}] [{i:#x}] [{neg:+}] [{zero:+}] [{neg:.1}]",
+			Options::default(),
+		)
+		.unwrap();
+		doc.set_value("f", 255.5);
+		doc.set("i", 255);
+		doc.set_value("neg", -0.0);
+		doc.set_value("zero", 0.0);
+
+		assert_eq!(doc.compile(), "[255.5] [255.5] [0xff] [-0] [+0] [-0.0]")
+	}
+
+	#[test]
+	fn format_spec_with_filters() {
+		let mut doc =
+			Document::from_str("[{name:>8|upper}] {unset:<4} {a:b}", Options::default()).unwrap();
+		doc.set("name", "crab");
+
+		assert_eq!(doc.compile(), "[    CRAB] {unset:<4} {a:b}")
+	}
+
+	#[test]
+	fn escape_html_by_extension() {
+		let mut doc = Document::from_file("test/escape.html", Options::default()).unwrap();
+		doc.set("title", "Fish & <Chips>");
+		doc.set("body", "<b>bold</b>");
+		doc.set_raw("footer", "<hr>");
+
+		assert_eq!(
+			doc.compile(),
+			"<h1 title=\"Fish &amp; &lt;Chips&gt;\">Fish &amp; &lt;Chips&gt;</h1>\n<b>bold</b>\n<hr>\n"
+		)
+	}
+
+	#[test]
+	fn escape_raw_through_filters() {
+		let mut doc = Document::from_str(
+			"{a|upper} {a|trim|truncate:3,\"\"} {a|replace:\"x\",\"y\"} {a|length} {b|upper}",
+			Options::default().escape(Escape::Html),
+		)
+		.unwrap();
+		doc.set_raw("a", "<b>x</b>");
+		doc.set("b", "<i>");
+
+		assert_eq!(doc.compile(), "<B>X</B> <b> <b>y</b> 8 &lt;I&gt;")
+	}
+
+	#[test]
+	fn escape_options() {
+		let template = "{a} {a|safe} {a|upper} {unset}";
+
+		let mut doc = Document::from_str(template, Options::default()).unwrap();
+		doc.set("a", "'<a>'");
+		assert_eq!(doc.compile(), "'<a>' '<a>' '<A>' {unset}");
+
+		let mut doc =
+			Document::from_str(template, Options::default().escape(Escape::Html)).unwrap();
+		doc.set("a", "'<a>'");
+		assert_eq!(
+			doc.compile(),
+			"&#39;&lt;a&gt;&#39; '<a>' &#39;&lt;A&gt;&#39; {unset}"
+		);
+
+		let mut doc =
+			Document::from_file("test/escape.html", Options::default().escape(Escape::Never))
+				.unwrap();
+		doc.set("title", "<");
+		doc.set("body", "<");
+		doc.set("footer", "<");
+		assert_eq!(doc.compile(), "<h1 title=\"<\"><</h1>\n<\n<\n");
+	}
+
+	#[test]
+	fn escape_builtin() {
+		let value = "a <b> & 'c' \"d\"/é";
+		let escaped = |escape: Escape| escape.escape(value);
+
+		assert_eq!(
+			escaped(Escape::Html),
+			"a &lt;b&gt; &amp; &#39;c&#39; &quot;d&quot;/é"
+		);
+		assert_eq!(
+			escaped(Escape::HtmlAttribute),
+			"a&#x20;&#x3C;b&#x3E;&#x20;&#x26;&#x20;&#x27;c&#x27;&#x20;&#x22;d&#x22;&#x2F;é"
+		);
+		assert_eq!(
+			escaped(Escape::Xml),
+			"a &lt;b&gt; &amp; &apos;c&apos; &quot;d&quot;/é"
+		);
+		assert_eq!(
+			escaped(Escape::Json),
+			"a \\u003cb\\u003e \\u0026 'c' \\\"d\\\"/é"
+		);
+		assert_eq!(
+			escaped(Escape::Url),
+			"a%20%3Cb%3E%20%26%20%27c%27%20%22d%22%2F%C3%A9"
+		);
+		assert_eq!(escaped(Escape::Shell), "'a <b> & '\\''c'\\'' \"d\"/é'");
+		assert_eq!(escaped(Escape::Never), value);
+	}
+
+	#[test]
+	fn escape_filter_and_custom() {
+		let options = Options::default().escape(Escape::custom(|s: &str| s.replace('x', "y")));
+		let mut doc = Document::from_str(
+			"{a} {a|escape:\"url\"} {a|escape:\"shell\"} {a|escape:\"nope\"}",
+			options,
+		)
+		.unwrap();
+		doc.set("a", "x x");
+
+		assert_eq!(doc.compile(), "y y x%20x 'x x' {a|escape:\"nope\"}")
+	}
+
+	#[test]
+	fn escape_by_extension() {
+		let mut doc = Document::from_file("test/escape.json", Options::default()).unwrap();
+		doc.set("name", "\"quoted\"");
+
+		assert_eq!(doc.compile(), "{\"name\": \"\\\"quoted\\\"\"}\n")
+	}
+
+	#[test]
+	fn escape_json_filter_in_json() {
+		let mut doc = Document::from_file("test/escape_filter.json", Options::default()).unwrap();
+		doc.set("name", "\"q\"");
+		doc.set_value("list", vec!["a", "b"]);
+
+		assert_eq!(
+			doc.compile(),
+			"{\"a\": {\"name\": \"\\\"q\\\"\", \"json\": \"\\\"q\\\"\", \"list\": [\"a\",\"b\"]}}\n"
+		)
+	}
+
+	#[test]
+	fn braces_in_quotes() {
+		let mut doc =
+			Document::from_str("{%pattern p sep=\"} {\"}{v}{%end}", Options::default()).unwrap();
+		doc.fill_pattern("p", ["a", "b"], |pat, v| pat.set("v", v))
+			.unwrap();
+
+		assert_eq!(doc.compile(), "a} {b")
+	}
+
 	#[test]
 	fn include_test() {
 		let doc = Document::from_file("test/include_test.bpl", Options::default()).unwrap();
@@ -206,9 +693,7 @@ mod test {
 			vec![
 				Token::Text("Before the include!\n".into()),
 				Token::Text("The included file! With a ".into()),
-				Token::Variable {
-					name: "variable".into()
-				},
+				var("variable"),
 				Token::Text("!".into()),
 				Token::Text("\naand after~".into())
 			]
@@ -227,9 +712,7 @@ mod test {
 			vec![
 				Token::Text("Testing IncludeMethod::Path here...\n".into()),
 				Token::Text("I'm in a subdir :D\n".into()),
-				Token::Variable {
-					name: "variable".into()
-				},
+				var("variable"),
 				Token::Text("!".into())
 			]
 		)
@@ -243,13 +726,13 @@ mod test {
 			doc.tokens,
 			vec![Token::Pattern {
 				pattern_name: String::from("name"),
+				separator: None,
 				tokens: vec![Token::IfSet {
-					variable_name: String::from("variable"),
-					tokens: vec![Token::Variable {
-						name: String::from("variable")
-					}],
+					variable: path("variable"),
+					tokens: vec![var("variable")],
 					else_tokens: None
-				}]
+				}],
+				empty_tokens: None
 			}]
 		)
 	}
@@ -275,6 +758,34 @@ mod test {
 		assert_eq!(doc.compile(), "barset!")
 	}
 
+	#[test]
+	fn ifset_structured_values() {
+		let mut doc = Document::from_str(
+			"{%if-set flag}flag{%end}{%if-set list}list{%end}{%if-set empty}empty{%end}{%if-set zero}zero{%end}",
+			Options::default(),
+		)
+		.unwrap();
+		doc.set_value("flag", false);
+		doc.set_value("list", vec!["one"]);
+		doc.set_value("empty", Vec::<Value>::new());
+		doc.set_value("zero", 0);
+
+		assert_eq!(doc.compile(), "listzero")
+	}
+
+	#[test]
+	fn compile_structured_values() {
+		let mut doc =
+			Document::from_str("{int} {float} {bool} {list} {null}.", Options::default()).unwrap();
+		doc.set_value("int", 12);
+		doc.set_value("float", 1.5);
+		doc.set_value("bool", true);
+		doc.set_value("list", vec![1, 2, 3]);
+		doc.set_value("null", Value::Null);
+
+		assert_eq!(doc.compile(), "12 1.5 true 1, 2, 3 .")
+	}
+
 	#[test]
 	fn iftest_else() {
 		let doc = Document::from_str(
@@ -287,19 +798,200 @@ mod test {
 	}
 
 	#[test]
-	fn pattern_parse() {
-		let doc = Document::from_str("{%pattern name}blah{variable}lah{%end}", Options::default())
-			.unwrap();
-
-		assert_eq!(
-			doc.get_pattern("name").unwrap().tokens,
-			vec![
-				Token::Text(String::from("blah")),
-				Token::Variable {
-					name: String::from("variable")
-				},
-				Token::Text(String::from("lah"))
-			]
+	fn ifunset() {
+		let mut doc = Document::from_str(
+			"{%if-unset foo}nofoo{%end}{%unless bar}nobar{%else}bar{%end}{%if-unset baz}nobaz{%else}baz{%end}",
+			Options::default(),
+		)
+		.unwrap();
+		doc.set("foo", "");
+		doc.set("baz", "set!");
+
+		assert_eq!(doc.compile(), "nofoonobarbaz")
+	}
+
+	#[test]
+	fn ifunset_parse() {
+		let doc = Document::from_str("{%unless foo}a{%else}b{%end}", Options::default()).unwrap();
+
+		assert_eq!(
+			doc.tokens,
+			vec![Token::IfUnset {
+				variable: path("foo"),
+				tokens: vec![Token::Text("a".into())],
+				else_tokens: Some(vec![Token::Text("b".into())])
+			}]
+		)
+	}
+
+	#[test]
+	fn else_if_chain() {
+		let source = "{%if-set a}a{%else-if-set b}b{%else-if-unset c}not c{%else}c{%end}";
+
+		let doc = Document::from_str(source, Options::default()).unwrap();
+		assert_eq!(
+			doc.tokens,
+			vec![Token::IfSet {
+				variable: path("a"),
+				tokens: vec![Token::Text("a".into())],
+				else_tokens: Some(vec![Token::IfSet {
+					variable: path("b"),
+					tokens: vec![Token::Text("b".into())],
+					else_tokens: Some(vec![Token::IfUnset {
+						variable: path("c"),
+						tokens: vec![Token::Text("not c".into())],
+						else_tokens: Some(vec![Token::Text("c".into())])
+					}])
+				}])
+			}]
+		);
+
+		let mut doc = Document::from_str(source, Options::default()).unwrap();
+		doc.set("b", "yes");
+		assert_eq!(doc.compile(), "b");
+
+		let mut doc = Document::from_str(source, Options::default()).unwrap();
+		doc.set("c", "yes");
+		assert_eq!(doc.compile(), "c");
+
+		let doc = Document::from_str(source, Options::default()).unwrap();
+		assert_eq!(doc.compile(), "not c");
+	}
+
+	#[test]
+	fn else_if_after_else() {
+		let result = Document::from_str(
+			"{%if-set a}a{%else}b{%else-if-set c}c{%end}",
+			Options::default(),
+		);
+
+		assert!(matches!(result, Err(ParseError::MisplacedCommand { .. })))
+	}
+
+	#[test]
+	fn else_if_outside_conditional() {
+		for source in [
+			"{%pattern p}{%else-if-set y}a{%end}",
+			"{%for x in xs}{x}{%else-if-set y}a{%end}",
+			"a{%else-if-set y}b",
+		] {
+			let result = Document::from_str(source, Options::default());
+			assert!(
+				matches!(result, Err(ParseError::MisplacedCommand { .. })),
+				"{}",
+				source
+			)
+		}
+	}
+
+	#[test]
+	fn if_parse() {
+		let doc = Document::from_str(
+			"{%if not (a == \"x\" or b < 2)}yes{%end}",
+			Options::default(),
+		)
+		.unwrap();
+
+		assert_eq!(
+			doc.tokens,
+			vec![Token::If {
+				condition: Expression::Not(Box::new(Expression::Or(
+					Box::new(Expression::Compare {
+						left: Box::new(Expression::Variable(path("a"))),
+						comparison: Comparison::Equal,
+						right: Box::new(Expression::Literal(Value::from("x")))
+					}),
+					Box::new(Expression::Compare {
+						left: Box::new(Expression::Variable(path("b"))),
+						comparison: Comparison::Less,
+						right: Box::new(Expression::Literal(Value::from(2)))
+					})
+				))),
+				tokens: vec![Token::Text("yes".into())],
+				else_tokens: None
+			}]
+		)
+	}
+
+	#[test]
+	fn if_invalid_expression() {
+		for source in [
+			"{%if a ==}{%end}",
+			"{%if (a}{%end}",
+			"{%if a b}{%end}",
+			"{%if \"open}{%end}",
+			"{%if a = b}{%end}",
+		] {
+			assert!(
+				Document::from_str(source, Options::default()).is_err(),
+				"{} parsed",
+				source
+			);
+		}
+	}
+
+	#[test]
+	fn if_compile() {
+		let source = "{%if status == \"paid\"}paid{%else-if due < 0 and not waived}overdue{%else-if amount >= limit or (vip and amount > 0)}big{%else}open{%end}";
+
+		let compile = |status: &str, due: i64, waived: bool, amount: f64, vip: bool| {
+			let mut doc = Document::from_str(source, Options::default()).unwrap();
+			doc.set("status", status);
+			doc.set("due", due);
+			doc.set_value("waived", waived);
+			doc.set_value("amount", amount);
+			doc.set_value("limit", 100);
+			doc.set_value("vip", vip);
+			doc.compile()
+		};
+
+		assert_eq!(compile("paid", -1, false, 0.0, false), "paid");
+		assert_eq!(compile("open", -1, false, 0.0, false), "overdue");
+		assert_eq!(compile("open", -1, true, 0.0, false), "open");
+		assert_eq!(compile("open", 3, false, 100.0, false), "big");
+		assert_eq!(compile("open", 3, false, 0.5, true), "big");
+		assert_eq!(compile("open", 3, false, 0.5, false), "open");
+	}
+
+	#[test]
+	fn if_compile_with_set() {
+		let mut doc = Document::from_str(
+			"{%if total > limit}over{%else}under{%end} {%if paid == true}paid{%end}{%if paid}!{%end} {%if late}late{%else}on time{%end}",
+			Options::default(),
+		)
+		.unwrap();
+		doc.set("total", 10);
+		doc.set("limit", 9);
+		doc.set("paid", true);
+		doc.set("late", false);
+
+		assert_eq!(doc.compile(), "over paid! on time")
+	}
+
+	#[test]
+	fn if_unset_and_null() {
+		let mut doc = Document::from_str(
+			"{%if missing == null}a{%end}{%if missing}b{%end}{%if name != missing}c{%end}",
+			Options::default(),
+		)
+		.unwrap();
+		doc.set("name", "Ferris");
+
+		assert_eq!(doc.compile(), "ac");
+	}
+
+	#[test]
+	fn pattern_parse() {
+		let doc = Document::from_str("{%pattern name}blah{variable}lah{%end}", Options::default())
+			.unwrap();
+
+		assert_eq!(
+			doc.get_pattern("name").unwrap().tokens,
+			vec![
+				Token::Text(String::from("blah")),
+				var("variable"),
+				Token::Text(String::from("lah"))
+			]
 		)
 	}
 
@@ -320,6 +1012,380 @@ mod test {
 		assert_eq!(doc.compile(), String::from("-one--two-"))
 	}
 
+	#[test]
+	fn for_loop_variables() {
+		let mut doc = Document::from_str(
+			"{%for item in items}{loop.index}/{loop.length}:{item}{%if-set loop.last}.{%else}, {%end}{%end}",
+			Options::default(),
+		)
+		.unwrap();
+		doc.set_value("items", vec!["a", "b", "c"]);
+
+		assert_eq!(doc.compile(), "1/3:a, 2/3:b, 3/3:c.")
+	}
+
+	#[test]
+	fn pattern_loop_variables() {
+		let mut doc = Document::from_str(
+			"{%pattern row}{%if-set loop.first}<{%end}{loop.index0}{%if-set loop.last}>{%end}{%end}",
+			Options::default(),
+		)
+		.unwrap();
+
+		let row = doc.get_pattern("row").unwrap();
+		for _ in 0..3 {
+			doc.set_pattern(row.clone());
+		}
+
+		assert_eq!(doc.compile(), "<012>")
+	}
+
+	#[test]
+	fn pattern_inherits_later_variables() {
+		let mut doc = Document::from_str(
+			"{%pattern name}{site}: {variable}, {%end}",
+			Options::default(),
+		)
+		.unwrap();
+		doc.set("site", "early");
+
+		let mut pat = doc.get_pattern("name").unwrap();
+		pat.set("variable", "one");
+		doc.set_pattern(pat);
+
+		let mut pat = doc.get_pattern("name").unwrap();
+		pat.set("variable", "two");
+		pat.set("site", "own");
+		doc.set_pattern(pat);
+
+		doc.set("site", "late");
+
+		assert_eq!(doc.compile(), "late: one, own: two, ")
+	}
+
+	#[test]
+	fn pattern_separator() {
+		let mut doc = Document::from_str(
+			"{%pattern name sep=\", \"}{variable}{%end}.",
+			Options::default(),
+		)
+		.unwrap();
+
+		for variable in ["one", "two", "three"] {
+			let mut pat = doc.get_pattern("name").unwrap();
+			pat.set("variable", variable);
+			doc.set_pattern(pat);
+		}
+
+		assert_eq!(doc.compile(), "one, two, three.")
+	}
+
+	#[test]
+	fn pattern_invalid_separator() {
+		assert!(Document::from_str("{%pattern name sep=,}{%end}", Options::default()).is_err());
+		assert!(Document::from_str("{%pattern name foo}{%end}", Options::default()).is_err());
+	}
+
+	#[test]
+	fn pattern_empty() {
+		let source = "{%pattern name}{variable}{%empty}No items found{%end}";
+
+		let doc = Document::from_str(source, Options::default()).unwrap();
+		assert_eq!(doc.compile(), "No items found");
+
+		let mut doc = Document::from_str(source, Options::default()).unwrap();
+		let mut pat = doc.get_pattern("name").unwrap();
+		pat.set("variable", "one");
+		doc.set_pattern(pat);
+		assert_eq!(doc.compile(), "one");
+	}
+
+	#[test]
+	fn empty_outside_pattern() {
+		for source in [
+			"{%if-set x}a{%empty}b{%end}",
+			"{%for x in xs}{x}{%empty}none{%end}",
+			"a{%empty}b",
+		] {
+			let result = Document::from_str(source, Options::default());
+			assert!(
+				matches!(result, Err(ParseError::MisplacedCommand { .. })),
+				"{}",
+				source
+			)
+		}
+	}
+
+	#[test]
+	fn pattern_inside_ifset() {
+		let mut doc = Document::from_str(
+			"{%if-set show_table}<table>{%pattern row}<tr>{cell}</tr>{%end}</table>{%end}",
+			Options::default(),
+		)
+		.unwrap();
+		doc.set("show_table", "yes");
+
+		let mut row = doc.get_pattern("row").unwrap();
+		row.set("cell", "one");
+		doc.set_pattern(row);
+
+		assert_eq!(doc.compile(), "<table><tr>one</tr></table>")
+	}
+
+	#[test]
+	fn pattern_inside_pattern() {
+		let mut doc = Document::from_str(
+			"{%pattern table}[{%pattern row}{cell}{%end}]{%end}{%pattern list}{%pattern row}({cell}){%end}{%end}",
+			Options::default(),
+		)
+		.unwrap();
+
+		// The first row found is the one in table
+		assert_eq!(doc.get_pattern("row").unwrap().tokens, vec![var("cell")]);
+		assert!(doc.get_pattern("table/list").is_none());
+
+		let mut table = doc.get_pattern("table").unwrap();
+		let mut own_row = table.get_pattern("row").unwrap();
+		own_row.set("cell", "own");
+		table.set_pattern(own_row);
+		doc.set_pattern(table);
+		doc.set_pattern(doc.get_pattern("table").unwrap());
+
+		let mut shared_row = doc.get_pattern("table/row").unwrap();
+		shared_row.set("cell", "shared");
+		doc.set_pattern(shared_row);
+
+		doc.set_pattern(doc.get_pattern("list").unwrap());
+		let mut list_row = doc.get_pattern("list/row").unwrap();
+		list_row.set("cell", "list");
+		doc.set_pattern(list_row);
+
+		assert_eq!(doc.compile(), "[own][shared](list)")
+	}
+
+	fn comment_tree(doc: &Document) -> Pattern {
+		let mut root = doc.get_pattern("node").unwrap();
+		root.set("text", "root");
+
+		let mut first = root.child();
+		first.set("text", "first");
+		let mut nested = first.child();
+		nested.set("text", "nested");
+		first.add_child(nested);
+
+		let mut second = root.child();
+		second.set("text", "second");
+
+		root.add_child(first);
+		root.add_child(second);
+		root
+	}
+
+	#[test]
+	fn recursive_pattern() {
+		let mut doc = Document::from_str(
+			"{%pattern node}({text}{%children node}){%end}",
+			Options::default(),
+		)
+		.unwrap();
+
+		let root = comment_tree(&doc);
+		doc.set_pattern(root);
+
+		assert_eq!(doc.compile(), "(root(first(nested))(second))")
+	}
+
+	#[test]
+	fn recursive_pattern_limit() {
+		let mut doc = Document::from_str(
+			"{%pattern node}({text}{%children node}){%end}",
+			Options::default().recursion_limit(2),
+		)
+		.unwrap();
+
+		let root = comment_tree(&doc);
+		doc.set_pattern(root);
+
+		assert_eq!(doc.compile(), "(root(first)(second))")
+	}
+
+	#[test]
+	fn pattern_fill_iter() {
+		let mut doc =
+			Document::from_str("{%pattern name}-{variable}-{%end}", Options::default()).unwrap();
+
+		doc.fill_pattern("name", ["one", "two"], |pat, variable| {
+			pat.set("variable", variable)
+		})
+		.unwrap();
+
+		assert_eq!(doc.compile(), String::from("-one--two-"))
+	}
+
+	#[test]
+	fn pattern_fill_unknown() {
+		let mut doc =
+			Document::from_str("{%pattern name}-{variable}-{%end}", Options::default()).unwrap();
+
+		let result = doc.fill_pattern("nope", ["one"], |pat, variable| {
+			pat.set("variable", variable)
+		});
+
+		assert!(matches!(result, Err(PatternError::UnknownPattern { name }) if name == "nope"))
+	}
+
+	#[test]
+	fn pattern_fill_maps() {
+		let mut doc =
+			Document::from_str("{%pattern row}{sku}: {count}\n{%end}", Options::default()).unwrap();
+
+		let rows = [("crab", 1), ("shell", 2)].map(|(sku, count)| {
+			let mut map = HashMap::new();
+			map.insert("sku", Value::from(sku));
+			map.insert("count", Value::from(count));
+			map
+		});
+		doc.fill_pattern_maps("row", rows).unwrap();
+
+		assert_eq!(doc.compile(), String::from("crab: 1\nshell: 2\n"))
+	}
+
+	#[cfg(feature = "serde")]
+	#[test]
+	fn pattern_fill_serialize() {
+		let mut doc =
+			Document::from_str("{%pattern row}{sku}: {tags.0}\n{%end}", Options::default())
+				.unwrap();
+
+		let rows = [
+			serde_json::json!({ "sku": "crab", "tags": ["red"] }),
+			serde_json::json!({ "sku": "shell", "tags": ["hard"] }),
+		];
+		doc.fill_pattern_serialize("row", rows).unwrap();
+
+		assert_eq!(doc.compile(), String::from("crab: red\nshell: hard\n"));
+
+		let mut doc = Document::from_str("{%pattern row}{%end}", Options::default()).unwrap();
+		let result = doc.fill_pattern_serialize("row", [1, 2]);
+		assert!(matches!(result, Err(PatternError::InvalidItem { .. })))
+	}
+
+	fn fill_rows(doc: &mut Document, rows: &[&str]) {
+		doc.fill_pattern("row", rows.iter(), |pat, row| {
+			pat.set_key(*row);
+			pat.set("cell", row);
+		})
+		.unwrap();
+	}
+
+	fn row(doc: &Document, cell: &str) -> Pattern {
+		let mut pat = doc.get_pattern("row").unwrap();
+		pat.set_key(cell);
+		pat.set("cell", cell);
+		pat
+	}
+
+	#[test]
+	fn pattern_manage_by_index() {
+		let mut doc =
+			Document::from_str("{%pattern row sep=\",\"}{cell}{%end}", Options::default()).unwrap();
+		fill_rows(&mut doc, &["a", "b", "c"]);
+
+		doc.insert_pattern(0, row(&doc, "first")).unwrap();
+		doc.insert_pattern(4, row(&doc, "last")).unwrap();
+		assert!(matches!(
+			doc.insert_pattern(9, row(&doc, "nope")),
+			Err(PatternError::IndexOutOfBounds {
+				index: 9,
+				len: 5,
+				..
+			})
+		));
+
+		let old = doc.replace_pattern(2, row(&doc, "B")).unwrap();
+		assert_eq!(old.key(), Some("b"));
+
+		assert_eq!(doc.remove_pattern("row", 1).unwrap().key(), Some("a"));
+		assert!(doc.remove_pattern("row", 9).is_none());
+
+		assert_eq!(doc.clone().compile(), "first,B,c,last");
+
+		doc.clear_pattern("row");
+		assert!(doc.pattern_instances("row").is_empty());
+		assert_eq!(doc.compile(), "");
+	}
+
+	#[test]
+	fn pattern_manage_out_of_bounds() {
+		let mut doc = Document::from_str("{%pattern row}{cell}{%end}", Options::default()).unwrap();
+		let before = doc.clone();
+
+		assert!(doc.insert_pattern(1, row(&doc, "a")).is_err());
+		assert!(doc.replace_pattern(0, row(&doc, "a")).is_err());
+		assert_eq!(doc, before);
+	}
+
+	#[test]
+	fn pattern_manage_by_key() {
+		let mut doc =
+			Document::from_str("{%pattern row sep=\",\"}{cell}{%end}", Options::default()).unwrap();
+		fill_rows(&mut doc, &["a", "b", "c"]);
+
+		let mut replacement = row(&doc, "b");
+		replacement.set("cell", "B");
+		doc.replace_pattern_keyed(replacement).unwrap();
+		assert!(matches!(
+			doc.replace_pattern_keyed(row(&doc, "z")),
+			Err(PatternError::UnknownKey { .. })
+		));
+
+		assert_eq!(
+			doc.remove_pattern_keyed("row", "a").unwrap().key(),
+			Some("a")
+		);
+		assert!(doc.remove_pattern_keyed("row", "a").is_none());
+
+		assert_eq!(doc.compile(), "B,c");
+	}
+
+	#[test]
+	fn pattern_sort() {
+		let mut doc =
+			Document::from_str("{%pattern row sep=\",\"}{cell}{%end}", Options::default()).unwrap();
+		fill_rows(&mut doc, &["b", "c", "a"]);
+
+		doc.sort_pattern_by("row", |a, b| {
+			a.get("cell")
+				.map(|v| v.to_string())
+				.cmp(&b.get("cell").map(|v| v.to_string()))
+		});
+
+		assert_eq!(doc.compile(), "a,b,c");
+	}
+
+	#[test]
+	fn if_pattern() {
+		let source = "{%if-pattern row}<table>{%pattern row}<tr>{cell}</tr>{%end}</table>{patterns.row} rows{%else}No rows{%end}";
+
+		let doc = Document::from_str(source, Options::default()).unwrap();
+		assert_eq!(doc.compile(), "No rows");
+
+		let mut doc = Document::from_str(source, Options::default()).unwrap();
+		doc.fill_pattern("row", ["a", "b"], |pat, cell| pat.set("cell", cell))
+			.unwrap();
+		assert_eq!(doc.compile(), "<table><tr>a</tr><tr>b</tr></table>2 rows");
+	}
+
+	#[test]
+	fn pattern_count_shadowed() {
+		let mut doc = Document::from_str("{patterns.row}", Options::default()).unwrap();
+		let mut patterns = HashMap::new();
+		patterns.insert("row", "mine");
+		doc.set_value("patterns", patterns);
+
+		assert_eq!(doc.compile(), "mine");
+	}
+
 	#[test]
 	fn nested_scoped_commands() {
 		let doc = Document::from_str(
@@ -331,15 +1397,76 @@ mod test {
 			doc.tokens,
 			vec![Token::Pattern {
 				pattern_name: String::from("name"),
+				separator: None,
 				tokens: vec![Token::IfSet {
-					variable_name: String::from("var"),
+					variable: path("var"),
 					tokens: vec![],
 					else_tokens: None
-				}]
+				}],
+				empty_tokens: None
+			}]
+		)
+	}
+
+	#[test]
+	fn for_parse() {
+		let doc = Document::from_str(
+			"{%for item in order.items}{item.sku}{%end}",
+			Options::default(),
+		)
+		.unwrap();
+
+		assert_eq!(
+			doc.tokens,
+			vec![Token::For {
+				variable: String::from("item"),
+				iterable: path("order.items"),
+				tokens: vec![var("item.sku")]
 			}]
 		)
 	}
 
+	#[test]
+	fn for_invalid_arguments() {
+		assert!(Document::from_str("{%for item items}{%end}", Options::default()).is_err());
+		assert!(Document::from_str("{%for item.sku in items}{%end}", Options::default()).is_err());
+	}
+
+	#[test]
+	fn for_compile() {
+		let mut doc = Document::from_str(
+			"{%for item in items}[{item.name}: {%for tag in item.tags}{tag}{%end}]{%end}{%for x in unset}{x}{%end}",
+			Options::default(),
+		)
+		.unwrap();
+
+		let items: Vec<Value> = [("one", vec!["a", "b"]), ("two", vec![])]
+			.into_iter()
+			.map(|(name, tags)| {
+				let mut item = HashMap::new();
+				item.insert("name", Value::from(name));
+				item.insert("tags", Value::from(tags));
+				Value::from(item)
+			})
+			.collect();
+		doc.set_value("items", items);
+
+		assert_eq!(doc.compile(), "[one: ab][two: ]")
+	}
+
+	#[test]
+	fn for_shadows_document_variable() {
+		let mut doc = Document::from_str(
+			"{%for name in names}{name} {%end}{name}",
+			Options::default(),
+		)
+		.unwrap();
+		doc.set("name", "outside");
+		doc.set_value("names", vec!["Ferris", "Rusty"]);
+
+		assert_eq!(doc.compile(), "Ferris Rusty outside")
+	}
+
 	#[test]
 	fn variables_macro() {
 		let mut doc = Document::from_str("{foo} and {bar}", Options::default()).unwrap();
@@ -360,6 +1487,216 @@ mod test {
 		assert_eq!(doc.compile(), String::from("11"))
 	}
 
+	#[test]
+	fn include_path_variables() {
+		let options = Options::default().variable("theme", "dark");
+		let mut doc = Document::from_file("test/theme_include.bpl", options).unwrap();
+		doc.set("title", "Hi");
+		assert_eq!(doc.compile(), "<dark>Hi</dark>\n");
+
+		let mut doc = Document::from_file("test/theme_set.bpl", Options::default()).unwrap();
+		doc.set("title", "Hi");
+		assert_eq!(doc.compile(), "<light>Hi</light>\n");
+	}
+
+	#[test]
+	fn include_path_variables_at_compile() {
+		let doc = Document::from_file("test/theme_include.bpl", Options::default()).unwrap();
+		assert_eq!(
+			doc.tokens,
+			vec![
+				Token::Include {
+					path: "themes/{theme}/header.bpl".into(),
+					arguments: vec![],
+					only: false,
+				},
+				Token::Text("\n".into())
+			]
+		);
+
+		let mut light = doc.clone();
+		light.set("theme", "light");
+		light.set("title", "Hi");
+		assert_eq!(light.compile(), "<light>Hi</light>\n");
+
+		assert_eq!(doc.compile(), "{%include themes/{theme}/header.bpl}\n");
+	}
+
+	#[test]
+	fn include_path_variables_recursion_limit() {
+		let options = Options::default().recursion_limit(3);
+		let mut doc = Document::from_file("test/recursive_var.bpl", options).unwrap();
+		doc.set("file", "recursive_var.bpl");
+
+		// The template and then three includes deep
+		assert_eq!(doc.compile(), "....")
+	}
+
+	#[test]
+	fn lazy_include() {
+		assert!(Document::from_file("test/lazy_include.bpl", Options::default()).is_err());
+
+		let options = Options::default().lazy_includes(true);
+		let mut doc = Document::from_file("test/lazy_include.bpl", options).unwrap();
+		doc.set("variable", "x");
+		assert_eq!(
+			doc.tokens[0],
+			Token::Include {
+				path: "another.bpl".into(),
+				arguments: vec![],
+				only: false,
+			}
+		);
+
+		let mut shown = doc.clone();
+		assert_eq!(
+			doc.clone().compile(),
+			"The included file! With a x!||The included file! With a x!\n"
+		);
+		assert_eq!(doc.includes.len(), 1);
+
+		shown.set("show", "yes");
+		assert_eq!(
+			shown.compile(),
+			"The included file! With a x!|{%include missing.bpl}|The included file! With a x!\n"
+		);
+	}
+
+	#[test]
+	fn include_arguments() {
+		let mut doc = Document::from_file("test/card_include.bpl", Options::default()).unwrap();
+		doc.set("summary", "A summary");
+		doc.set("heading", "shout");
+		doc.set("site", "bempline");
+		doc.set("title", "Not this");
+
+		assert_eq!(
+			doc.compile(),
+			"<div class=\"card\"><h2>Hello</h2>A summary bempline</div>\n<div class=\"card\"><h2>shout</h2>no body no site</div>\n"
+		)
+	}
+
+	#[test]
+	fn include_arguments_missing() {
+		let result = Document::from_file("test/missing_include.bpl", Options::default());
+		assert!(matches!(
+			result,
+			Err(ParseError::CanonicalizationError { .. })
+		));
+
+		let doc = Document::from_file(
+			"test/missing_include.bpl",
+			Options::default().lazy_includes(true),
+		)
+		.unwrap();
+		assert_eq!(doc.compile(), "{%include missing.bpl title=\"x\"}\n")
+	}
+
+	#[test]
+	fn include_arguments_recursive() {
+		let options = Options::default().recursion_limit(3);
+		let mut doc = Document::from_file("test/recursive_arguments.bpl", options).unwrap();
+		doc.set("mark", "!");
+
+		assert_eq!(doc.compile(), "...!")
+	}
+
+	#[test]
+	fn include_recursion_limit() {
+		let options = Options::default().lazy_includes(true).recursion_limit(3);
+		let doc = Document::from_file("test/recursive.bpl", options).unwrap();
+
+		// The template and then three includes deep
+		assert_eq!(doc.compile(), "....")
+	}
+
+	#[test]
+	fn macro_call() {
+		let mut doc = Document::from_str(
+			"{%macro button label href kind=\"primary\"}<a class=\"{kind}\" href=\"{href}\">{label}</a>{%end}{%call button label=\"Buy\" href={buy_url}} {%call button href=\"/\" kind=\"secondary\"} {%call nope a=1}",
+			Options::default(),
+		)
+		.unwrap();
+		doc.set("buy_url", "/buy");
+
+		assert_eq!(
+			doc.compile(),
+			"<a class=\"primary\" href=\"/buy\">Buy</a> <a class=\"secondary\" href=\"/\">{label}</a> {%call nope a=1}"
+		)
+	}
+
+	#[test]
+	fn macro_omitted_parameter() {
+		let mut doc = Document::from_str(
+			"{%macro m a b}{a}-{b}{%if-set b}!{%end}{%end}{%call m a=\"1\"} {b}",
+			Options::default(),
+		)
+		.unwrap();
+		doc.set("b", "LEAK");
+
+		assert_eq!(doc.compile(), "1-{b} LEAK")
+	}
+
+	#[test]
+	fn macro_in_pattern() {
+		let mut doc = Document::from_str(
+			"{%pattern item}{%call tag text=name}{%end}{%macro tag text}[{text}]{%end}",
+			Options::default(),
+		)
+		.unwrap();
+		doc.fill_pattern("item", ["a", "b"], |pat, name| pat.set("name", name))
+			.unwrap();
+
+		assert_eq!(doc.compile(), "[a][b]")
+	}
+
+	#[test]
+	fn macro_import() {
+		let mut doc = Document::from_file("test/import_macros.bpl", Options::default()).unwrap();
+		doc.set("who", "Rusty");
+
+		assert_eq!(doc.compile(), "Hello, Ferris! Bye, Rusty!\n")
+	}
+
+	#[test]
+	fn import_namespace() {
+		let mut doc =
+			Document::from_file("test/import_components.bpl", Options::default()).unwrap();
+		assert!(doc.get_pattern("ui.nope").is_none());
+
+		let card = doc.get_pattern("ui.card").unwrap();
+		assert_eq!(card.name(), "ui.card");
+
+		doc.fill_pattern("ui.card", ["a", "b"], |pat, name| pat.set("name", name))
+			.unwrap();
+
+		assert_eq!(
+			doc.compile(),
+			"<div>Hi<button>More</button></div>|<li><button>a</button></li><li><button>b</button></li>|{%call card title=\"x\"}\n"
+		)
+	}
+
+	#[test]
+	fn import_namespace_recursive_pattern() {
+		let mut doc = Document::from_file("test/import_tree.bpl", Options::default()).unwrap();
+
+		let mut root = doc.get_pattern("ui.node").unwrap();
+		root.set("t", "r");
+		let mut child = root.child();
+		child.set("t", "c");
+		root.add_child(child);
+		doc.set_pattern(root);
+
+		assert_eq!(doc.compile(), "(r(c)) 1\n")
+	}
+
+	#[test]
+	fn macro_must_be_top_level() {
+		let doc = Document::from_str("{%if-set a}{%macro m}{%end}{%end}", Options::default());
+
+		assert!(matches!(doc, Err(ParseError::MisplacedCommand { .. })))
+	}
+
 	#[test]
 	fn wrapping_include() {
 		let expected = "<html><head>Foo<title>Test!</title></head></html>";
@@ -1,10 +1,22 @@
-use std::path::PathBuf;
+use core::fmt;
+use std::{collections::HashMap, path::PathBuf, sync::Arc};
+
+use crate::{
+	escape::{self, Escaper},
+	filter::CustomFilters,
+	FilterError, Value,
+};
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct Options {
 	pub unknown_include: ErrorLevel,
 	pub unset_varaible: ErrorLevel,
 	pub include_method: IncludeMethod,
+	pub recursion_limit: usize,
+	pub filters: CustomFilters,
+	pub escape: Escape,
+	pub variables: HashMap<String, Value>,
+	pub lazy_includes: bool,
 }
 
 impl Options {
@@ -41,6 +53,72 @@ impl Options {
 		self.include_method = include_path;
 		self
 	}
+
+	/// Sets how deep recursive patterns, those with a `{%children}` command,
+	/// and includes read while compiling may go. Children and includes
+	/// deeper than this are not compiled.
+	///
+	/// ### Default
+	/// By default this is set to `64`.
+	pub fn recursion_limit(mut self, limit: usize) -> Self {
+		self.recursion_limit = limit;
+		self
+	}
+
+	/// Register a filter that can be used in the template like `{sku|name}`.
+	/// It's given the value of the variable and any arguments and returns
+	/// the new value. If it returns an error the variable is passed through
+	/// as it was written.
+	///
+	/// A filter with the same name as a built in one replaces it.
+	pub fn filter<N, F>(mut self, name: N, filter: F) -> Self
+	where
+		N: Into<String>,
+		F: Fn(&Value, &[Value]) -> Result<Value, FilterError> + Send + Sync + 'static,
+	{
+		self.filters.insert(name.into(), filter);
+		self
+	}
+
+	/// Set a variable before the document is parsed, as if with a `{%set}` at
+	/// the very top. These can be used in the path of an include, like
+	/// `{%include themes/{theme}/header.bpl}`.
+	pub fn variable<K: Into<String>, V: Into<Value>>(mut self, key: K, value: V) -> Self {
+		self.variables.insert(key.into(), value.into());
+		self
+	}
+
+	/// Sets whether includes are read when the document is compiled instead
+	/// of when it's parsed. Each file is still only parsed once. An include
+	/// in a branch that isn't taken, like the body of an `{%if-set}` whose
+	/// variable isn't set, is never read at all, so it doesn't matter if the
+	/// file is missing. An include that can't be read is passed through as
+	/// text.
+	///
+	/// When this is `false`, includes with arguments are still read when the
+	/// document is parsed, and a missing file is an error, even though the
+	/// arguments are only used when it's compiled.
+	///
+	/// Patterns inside these includes can't be found with
+	/// [Document::get_pattern](crate::Document::get_pattern). `{%wrap-include}`
+	/// is always read when the document is parsed.
+	///
+	/// ### Default
+	/// By default this is set `false`.
+	pub fn lazy_includes(mut self, lazy: bool) -> Self {
+		self.lazy_includes = lazy;
+		self
+	}
+
+	/// Sets how the value of every variable is escaped when the document is
+	/// compiled. See [Escape] for more information.
+	///
+	/// ### Default
+	/// By default this is set to [Escape::Auto].
+	pub fn escape(mut self, escape: Escape) -> Self {
+		self.escape = escape;
+		self
+	}
 }
 
 impl Default for Options {
@@ -49,6 +127,11 @@ impl Default for Options {
 			unknown_include: ErrorLevel::Error,
 			unset_varaible: ErrorLevel::NoError,
 			include_method: IncludeMethod::Template,
+			recursion_limit: 64,
+			filters: CustomFilters::default(),
+			escape: Escape::Auto,
+			variables: HashMap::new(),
+			lazy_includes: false,
 		}
 	}
 }
@@ -74,6 +157,117 @@ pub enum IncludeMethod {
 	Path(PathBuf),
 }
 
+/// How variables are escaped during [Document::compile](crate::Document::compile).
+///
+/// **Auto** picks an escaper by the extension of the template. `.html` and `.htm` files are
+/// escaped with **Html**, `.xml` and `.svg` with **Xml**, `.json` with **Json** and `.sh` with
+/// **Shell**. Anything else, and documents not read from a file, aren't escaped.
+///
+/// **Never** writes every variable as it is.
+///
+/// **Custom** uses your own [Escaper].
+///
+/// A single variable can be escaped differently with the `escape` filter, like
+/// `{query|escape:"url"}`, which takes the names from [Escape::from_name]. Variables set with
+/// [Document::set_raw](crate::Document::set_raw), or with a `safe` filter like `{body|safe}`,
+/// are never escaped.
+#[derive(Clone)]
+pub enum Escape {
+	/// Escape by the extension of the template
+	Auto,
+	/// Don't escape anything
+	Never,
+	/// Replace `&`, `<`, `>`, `"` and `'` with HTML entities
+	Html,
+	/// Replace every ASCII character that isn't a letter or number with a
+	/// character reference, for unquoted HTML attributes
+	HtmlAttribute,
+	/// Replace `&`, `<`, `>`, `"` and `'` with XML entities
+	Xml,
+	/// Escape for the inside of a double quoted JSON string
+	Json,
+	/// Percent encode for a URL path segment or query parameter
+	Url,
+	/// Quote as a single word for a POSIX shell
+	Shell,
+	/// Use your own escaper
+	Custom(Arc<dyn Escaper>),
+}
+
+impl Escape {
+	pub fn custom<E: Escaper + 'static>(escaper: E) -> Self {
+		Escape::Custom(Arc::new(escaper))
+	}
+
+	/// Get a built in escaper by the name used in the `escape` filter: `html`,
+	/// `html-attribute`, `xml`, `json`, `url` or `shell`.
+	pub fn from_name(name: &str) -> Option<Self> {
+		match name {
+			"html" => Some(Escape::Html),
+			"html-attribute" => Some(Escape::HtmlAttribute),
+			"xml" => Some(Escape::Xml),
+			"json" => Some(Escape::Json),
+			"url" => Some(Escape::Url),
+			"shell" => Some(Escape::Shell),
+			_ => None,
+		}
+	}
+
+	/// The escaper used for a template with this extension when the escaping
+	/// is [Escape::Auto]
+	pub fn from_extension(extension: &str) -> Self {
+		match extension {
+			"html" | "htm" => Escape::Html,
+			"xml" | "svg" => Escape::Xml,
+			"json" => Escape::Json,
+			"sh" => Escape::Shell,
+			_ => Escape::Never,
+		}
+	}
+}
+
+impl Escaper for Escape {
+	/// [Escape::Auto] and [Escape::Never] leave the string as it is.
+	fn escape(&self, s: &str) -> String {
+		match self {
+			Escape::Auto | Escape::Never => s.to_owned(),
+			Escape::Html => escape::html(s),
+			Escape::HtmlAttribute => escape::html_attribute(s),
+			Escape::Xml => escape::xml(s),
+			Escape::Json => escape::json_string(s),
+			Escape::Url => escape::url_component(s),
+			Escape::Shell => escape::shell(s),
+			Escape::Custom(escaper) => escaper.escape(s),
+		}
+	}
+}
+
+impl fmt::Debug for Escape {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Escape::Auto => write!(f, "Auto"),
+			Escape::Never => write!(f, "Never"),
+			Escape::Html => write!(f, "Html"),
+			Escape::HtmlAttribute => write!(f, "HtmlAttribute"),
+			Escape::Xml => write!(f, "Xml"),
+			Escape::Json => write!(f, "Json"),
+			Escape::Url => write!(f, "Url"),
+			Escape::Shell => write!(f, "Shell"),
+			Escape::Custom(_) => write!(f, "Custom(..)"),
+		}
+	}
+}
+
+impl PartialEq for Escape {
+	/// Custom escapers are only equal if they're the very same one
+	fn eq(&self, other: &Self) -> bool {
+		match (self, other) {
+			(Escape::Custom(left), Escape::Custom(right)) => Arc::ptr_eq(left, right),
+			_ => core::mem::discriminant(self) == core::mem::discriminant(other),
+		}
+	}
+}
+
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum ErrorLevel {
 	Error,
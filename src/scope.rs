@@ -0,0 +1,216 @@
+use std::{borrow::Cow, collections::HashMap};
+
+use crate::{
+	document::{pattern_base_name, Macro},
+	value::VariablePath,
+	Document, Pattern, Value,
+};
+
+/// The variables and pattern instances visible while compiling a
+/// [Document](crate::Document).
+///
+/// Every scope can have a parent. Variables are looked for in the innermost
+/// scope first, so a loop variable shadows a document variable of the same
+/// name.
+pub(crate) struct Scope<'a> {
+	variables: Cow<'a, HashMap<String, Value>>,
+	patterns: Option<PatternFrame<'a>>,
+	/// The macros defined in the document this scope is for
+	macros: Option<&'a HashMap<String, Macro>>,
+	parent: Option<&'a Scope<'a>>,
+	/// Names that aren't looked for in the parent, even if they aren't set
+	/// in this scope
+	hidden: Vec<&'a str>,
+	/// How many pattern instances deep this scope is
+	depth: usize,
+}
+
+/// The pattern instances set on a document or on a pattern instance
+#[derive(Clone, Copy)]
+struct PatternFrame<'a> {
+	instances: &'a HashMap<String, Vec<Pattern>>,
+	/// The name of the pattern this is an instance of, or `None` if this is
+	/// the document being compiled
+	instance_of: Option<&'a str>,
+}
+
+impl<'a> Scope<'a> {
+	pub fn new(document: &'a Document) -> Self {
+		Self {
+			variables: Cow::Borrowed(&document.variables),
+			patterns: Some(PatternFrame {
+				instances: &document.patterns,
+				instance_of: None,
+			}),
+			macros: Some(&document.macros),
+			parent: None,
+			hidden: vec![],
+			depth: 0,
+		}
+	}
+
+	/// Create a scope inside this one
+	pub fn child(&'a self, variables: Cow<'a, HashMap<String, Value>>) -> Self {
+		Self {
+			variables,
+			patterns: None,
+			macros: None,
+			parent: Some(self),
+			hidden: vec![],
+			depth: self.depth,
+		}
+	}
+
+	/// Create a scope for an included document. If it's `only`, it can't see
+	/// any of the variables, patterns or macros outside of it.
+	pub fn include(&'a self, document: &'a Document, only: bool) -> Self {
+		Self {
+			variables: Cow::Borrowed(&document.variables),
+			patterns: None,
+			macros: Some(&document.macros),
+			parent: if only { None } else { Some(self) },
+			hidden: vec![],
+			depth: self.depth + 1,
+		}
+	}
+
+	/// Create a scope for a call to a macro, holding its arguments. The
+	/// parameters of the macro are never looked up outside of the call, so
+	/// one that isn't given stays unset.
+	pub fn call(&'a self, found: &'a Macro, arguments: HashMap<String, Value>) -> Self {
+		Self {
+			variables: Cow::Owned(arguments),
+			patterns: None,
+			macros: None,
+			parent: Some(self),
+			hidden: found
+				.parameters
+				.iter()
+				.map(|(name, _)| name.as_str())
+				.collect(),
+			depth: self.depth + 1,
+		}
+	}
+
+	/// Create a scope for an instance of the pattern `pattern_name`
+	pub fn instance(&'a self, pattern_name: &'a str, instance: &'a Document) -> Self {
+		Self {
+			variables: Cow::Borrowed(&instance.variables),
+			patterns: Some(PatternFrame {
+				instances: &instance.patterns,
+				instance_of: Some(pattern_name),
+			}),
+			macros: None,
+			parent: Some(self),
+			hidden: vec![],
+			depth: self.depth + 1,
+		}
+	}
+
+	pub fn depth(&self) -> usize {
+		self.depth
+	}
+
+	/// Resolve a variable path, starting at the innermost scope. A variable
+	/// whose name is the whole dotted path takes precedence over walking into
+	/// nested values, and the first scope that has the root of the path is
+	/// the one it resolves in.
+	///
+	/// If nothing has set a variable named `patterns`, then `patterns.name`
+	/// is the number of instances of the pattern `name`.
+	pub fn lookup(&self, path: &VariablePath) -> Option<Cow<'_, Value>> {
+		match self.lookup_variable(path) {
+			Some(value) => Some(Cow::Borrowed(value)),
+			None => match (path.root(), path.rest()) {
+				// Imported patterns have dots in their name, like `ui.card`
+				("patterns", rest) if !rest.is_empty() => {
+					let count = self.instances(&rest.join(".")).map(|pats| pats.len());
+					Some(Cow::Owned(Value::from(count.unwrap_or(0))))
+				}
+				_ => None,
+			},
+		}
+	}
+
+	fn lookup_variable(&self, path: &VariablePath) -> Option<&Value> {
+		if !path.rest().is_empty() {
+			if let Some(value) = self.variables.get(&path.to_string()) {
+				return Some(value);
+			}
+		}
+
+		match self.variables.get(path.root()) {
+			Some(value) => value.get_path(path.rest()),
+			None if self.hidden.contains(&path.root()) => None,
+			None => self.parent.and_then(|parent| parent.lookup_variable(path)),
+		}
+	}
+
+	/// Find the macro `name`, starting at the innermost scope
+	pub fn find_macro(&self, name: &str) -> Option<&'a Macro> {
+		match self.macros.and_then(|macros| macros.get(name)) {
+			Some(found) => Some(found),
+			None => self.parent.and_then(|parent| parent.find_macro(name)),
+		}
+	}
+
+	/// Find the instances for the pattern `name` where it appears in this
+	/// scope. Instances set on the innermost pattern instance are used first.
+	/// Going outwards, instances set with a path from that scope, like
+	/// `table/row`, are preferred over those set with just the name.
+	pub fn instances(&self, name: &str) -> Option<&'a [Pattern]> {
+		let mut path = name.to_owned();
+		let mut current = Some(self);
+
+		while let Some(scope) = current {
+			if let Some(PatternFrame {
+				instances,
+				instance_of,
+			}) = scope.patterns
+			{
+				let found = instances
+					.get(&path)
+					.filter(|pats| !pats.is_empty())
+					.or_else(|| instances.get(name).filter(|pats| !pats.is_empty()));
+
+				if let Some(pats) = found {
+					return Some(pats);
+				}
+
+				if let Some(instance_of) = instance_of {
+					path = format!("{}/{}", instance_of, path);
+				}
+			}
+
+			current = scope.parent;
+		}
+
+		None
+	}
+
+	/// Find the children of the nearest instance of the pattern `name`. These
+	/// are never taken from further out, otherwise every instance would be
+	/// its own child.
+	pub fn children(&self, name: &str) -> Option<&'a [Pattern]> {
+		let mut current = Some(self);
+
+		while let Some(scope) = current {
+			if let Some(PatternFrame {
+				instances,
+				instance_of: Some(instance_of),
+			}) = scope.patterns
+			{
+				if pattern_base_name(instance_of) == name {
+					return instances
+						.get(name)
+						.map(|pats| &pats[..])
+						.filter(|pats| !pats.is_empty());
+				}
+			}
+
+			current = scope.parent;
+		}
+
+		None
+	}
+}
@@ -0,0 +1,372 @@
+use core::fmt;
+use std::collections::{BTreeMap, HashMap};
+
+/// The value of a variable in a [Document](crate::Document).
+///
+/// Anything that implements [fmt::Display] can still be given to
+/// [Document::set](crate::Document::set), in which case it's stored as a
+/// [Value::String]. Use [Document::set_value](crate::Document::set_value) to
+/// keep the structure around until the document is compiled.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum Value {
+	#[default]
+	Null,
+	Bool(bool),
+	Integer(i64),
+	Float(f64),
+	String(String),
+	/// A string that is never escaped, from
+	/// [Document::set_raw](crate::Document::set_raw) or the `safe` filter
+	Safe(String),
+	List(Vec<Value>),
+	Map(BTreeMap<String, Value>),
+}
+
+impl Value {
+	/// Whether or not this value counts as set for conditionals like `{%if-set}`.
+	///
+	/// Null, `false`, the empty string, and empty lists and maps are all
+	/// considered unset. Numbers are always set, even zero.
+	pub fn is_truthy(&self) -> bool {
+		match self {
+			Value::Null => false,
+			Value::Bool(b) => *b,
+			Value::Integer(_) | Value::Float(_) => true,
+			Value::String(s) | Value::Safe(s) => !s.is_empty(),
+			Value::List(l) => !l.is_empty(),
+			Value::Map(m) => !m.is_empty(),
+		}
+	}
+
+	pub fn is_null(&self) -> bool {
+		matches!(self, Value::Null)
+	}
+
+	pub fn as_str(&self) -> Option<&str> {
+		match self {
+			Value::String(s) | Value::Safe(s) => Some(s),
+			_ => None,
+		}
+	}
+
+	pub fn as_bool(&self) -> Option<bool> {
+		match self {
+			Value::Bool(b) => Some(*b),
+			_ => None,
+		}
+	}
+
+	pub fn as_i64(&self) -> Option<i64> {
+		match self {
+			Value::Integer(i) => Some(*i),
+			_ => None,
+		}
+	}
+
+	/// Integers are widened to floats
+	pub fn as_f64(&self) -> Option<f64> {
+		match self {
+			Value::Integer(i) => Some(*i as f64),
+			Value::Float(f) => Some(*f),
+			_ => None,
+		}
+	}
+
+	pub fn as_list(&self) -> Option<&[Value]> {
+		match self {
+			Value::List(l) => Some(l),
+			_ => None,
+		}
+	}
+
+	pub fn as_map(&self) -> Option<&BTreeMap<String, Value>> {
+		match self {
+			Value::Map(m) => Some(m),
+			_ => None,
+		}
+	}
+
+	/// Convert anything that implements `Serialize` into a value.
+	#[cfg(feature = "serde")]
+	pub fn from_serialize<T: serde::Serialize>(value: &T) -> Result<Value, serde_json::Error> {
+		serde_json::to_value(value).map(Value::from)
+	}
+
+	/// Write this value as JSON. Floats that aren't finite become `null`.
+	pub fn to_json(&self) -> String {
+		let mut ret = String::new();
+		self.write_json(&mut ret);
+		ret
+	}
+
+	fn write_json(&self, ret: &mut String) {
+		match self {
+			Value::Null => ret.push_str("null"),
+			Value::Bool(b) => ret.push_str(&b.to_string()),
+			Value::Integer(i) => ret.push_str(&i.to_string()),
+			Value::Float(f) if f.is_finite() => ret.push_str(&f.to_string()),
+			Value::Float(_) => ret.push_str("null"),
+			Value::String(s) | Value::Safe(s) => write_json_string(s, ret),
+			Value::List(l) => {
+				ret.push('[');
+				for (idx, value) in l.iter().enumerate() {
+					if idx > 0 {
+						ret.push(',');
+					}
+					value.write_json(ret);
+				}
+				ret.push(']');
+			}
+			Value::Map(m) => {
+				ret.push('{');
+				for (idx, (key, value)) in m.iter().enumerate() {
+					if idx > 0 {
+						ret.push(',');
+					}
+					write_json_string(key, ret);
+					ret.push(':');
+					value.write_json(ret);
+				}
+				ret.push('}');
+			}
+		}
+	}
+
+	/// Get a single step into this value. Maps are indexed by key and lists
+	/// by a numeric index. Every other value has nothing inside it.
+	pub fn get(&self, segment: &str) -> Option<&Value> {
+		match self {
+			Value::Map(m) => m.get(segment),
+			Value::List(l) => segment.parse::<usize>().ok().and_then(|idx| l.get(idx)),
+			_ => None,
+		}
+	}
+
+	/// Follow every segment in turn, like [Value::get] repeatedly.
+	pub fn get_path<S: AsRef<str>>(&self, segments: &[S]) -> Option<&Value> {
+		segments
+			.iter()
+			.try_fold(self, |value, segment| value.get(segment.as_ref()))
+	}
+}
+
+fn write_json_string(s: &str, ret: &mut String) {
+	ret.push('"');
+	for ch in s.chars() {
+		match ch {
+			'"' => ret.push_str("\\\""),
+			'\\' => ret.push_str("\\\\"),
+			'\n' => ret.push_str("\\n"),
+			'\r' => ret.push_str("\\r"),
+			'\t' => ret.push_str("\\t"),
+			ch if (ch as u32) < 0x20 => ret.push_str(&format!("\\u{:04x}", ch as u32)),
+			ch => ret.push(ch),
+		}
+	}
+	ret.push('"');
+}
+
+/// A dot separated path to a variable, like `user.name` or `order.items.0.sku`.
+///
+/// The first segment names a variable in the [Document](crate::Document) and
+/// the rest walk through nested maps and lists.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct VariablePath {
+	segments: Vec<String>,
+}
+
+impl VariablePath {
+	/// Parse a path from its dotted form. Returns `None` if any segment is
+	/// empty or contains whitespace.
+	pub fn parse<S: AsRef<str>>(s: S) -> Option<Self> {
+		let segments: Vec<String> = s.as_ref().split('.').map(str::to_owned).collect();
+
+		if segments
+			.iter()
+			.any(|seg| seg.is_empty() || seg.contains(char::is_whitespace))
+		{
+			None
+		} else {
+			Some(Self { segments })
+		}
+	}
+
+	/// The name of the variable this path starts at
+	pub fn root(&self) -> &str {
+		&self.segments[0]
+	}
+
+	pub fn segments(&self) -> &[String] {
+		&self.segments
+	}
+
+	/// The segments after the root
+	pub fn rest(&self) -> &[String] {
+		&self.segments[1..]
+	}
+}
+
+impl fmt::Display for VariablePath {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}", self.segments.join("."))
+	}
+}
+
+impl fmt::Display for Value {
+	/// Null is written as nothing at all and lists are comma separated. Maps
+	/// are written as comma separated `key: value` pairs.
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Value::Null => Ok(()),
+			Value::Bool(b) => write!(f, "{}", b),
+			Value::Integer(i) => write!(f, "{}", i),
+			Value::Float(fl) => write!(f, "{}", fl),
+			Value::String(s) | Value::Safe(s) => write!(f, "{}", s),
+			Value::List(l) => {
+				for (idx, value) in l.iter().enumerate() {
+					if idx > 0 {
+						write!(f, ", ")?;
+					}
+					write!(f, "{}", value)?;
+				}
+				Ok(())
+			}
+			Value::Map(m) => {
+				for (idx, (key, value)) in m.iter().enumerate() {
+					if idx > 0 {
+						write!(f, ", ")?;
+					}
+					write!(f, "{}: {}", key, value)?;
+				}
+				Ok(())
+			}
+		}
+	}
+}
+
+impl From<&str> for Value {
+	fn from(s: &str) -> Self {
+		Value::String(s.to_owned())
+	}
+}
+
+impl From<String> for Value {
+	fn from(s: String) -> Self {
+		Value::String(s)
+	}
+}
+
+impl From<&String> for Value {
+	fn from(s: &String) -> Self {
+		Value::String(s.clone())
+	}
+}
+
+impl From<bool> for Value {
+	fn from(b: bool) -> Self {
+		Value::Bool(b)
+	}
+}
+
+macro_rules! from_integer {
+	($($int:ty),+) => {
+		$(
+			impl From<$int> for Value {
+				fn from(i: $int) -> Self {
+					Value::Integer(i as i64)
+				}
+			}
+		)+
+	};
+}
+
+from_integer!(i8, i16, i32, i64, u8, u16, u32);
+
+macro_rules! from_wide_integer {
+	($($int:ty),+) => {
+		$(
+			/// Falls back to a [Value::Float] if the integer does not fit in an `i64`
+			impl From<$int> for Value {
+				fn from(i: $int) -> Self {
+					match i64::try_from(i) {
+						Ok(i) => Value::Integer(i),
+						Err(_) => Value::Float(i as f64),
+					}
+				}
+			}
+		)+
+	};
+}
+
+from_wide_integer!(u64, usize, isize);
+
+impl From<f32> for Value {
+	fn from(f: f32) -> Self {
+		Value::Float(f as f64)
+	}
+}
+
+impl From<f64> for Value {
+	fn from(f: f64) -> Self {
+		Value::Float(f)
+	}
+}
+
+impl<T: Into<Value>> From<Option<T>> for Value {
+	fn from(opt: Option<T>) -> Self {
+		match opt {
+			Some(v) => v.into(),
+			None => Value::Null,
+		}
+	}
+}
+
+impl<T: Into<Value>> From<Vec<T>> for Value {
+	fn from(v: Vec<T>) -> Self {
+		Value::List(v.into_iter().map(Into::into).collect())
+	}
+}
+
+impl<T: Clone + Into<Value>> From<&[T]> for Value {
+	fn from(v: &[T]) -> Self {
+		Value::List(v.iter().cloned().map(Into::into).collect())
+	}
+}
+
+impl<K: Into<String>, V: Into<Value>> From<HashMap<K, V>> for Value {
+	fn from(m: HashMap<K, V>) -> Self {
+		Value::Map(m.into_iter().map(|(k, v)| (k.into(), v.into())).collect())
+	}
+}
+
+impl<K: Into<String>, V: Into<Value>> From<BTreeMap<K, V>> for Value {
+	fn from(m: BTreeMap<K, V>) -> Self {
+		Value::Map(m.into_iter().map(|(k, v)| (k.into(), v.into())).collect())
+	}
+}
+
+impl<V: Into<Value>> FromIterator<V> for Value {
+	fn from_iter<I: IntoIterator<Item = V>>(iter: I) -> Self {
+		Value::List(iter.into_iter().map(Into::into).collect())
+	}
+}
+
+#[cfg(feature = "serde")]
+impl From<serde_json::Value> for Value {
+	fn from(value: serde_json::Value) -> Self {
+		match value {
+			serde_json::Value::Null => Value::Null,
+			serde_json::Value::Bool(b) => Value::Bool(b),
+			serde_json::Value::Number(n) => match (n.as_i64(), n.as_u64()) {
+				(Some(i), _) => Value::Integer(i),
+				(None, Some(u)) => Value::from(u),
+				(None, None) => Value::Float(n.as_f64().unwrap_or(f64::NAN)),
+			},
+			serde_json::Value::String(s) => Value::String(s),
+			serde_json::Value::Array(a) => Value::List(a.into_iter().map(Value::from).collect()),
+			serde_json::Value::Object(o) => {
+				Value::Map(o.into_iter().map(|(k, v)| (k, Value::from(v))).collect())
+			}
+		}
+	}
+}